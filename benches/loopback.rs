@@ -0,0 +1,115 @@
+//! Bulk transfer between two stacks wired together through loopback devices.
+//!
+//! Run with `cargo bench`, the throughput is printed per buffer size, along
+//! with how many of the ACKs the sender got took the header prediction fast path.
+
+use std::{
+    io::{self, Write},
+    net::{Shutdown, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use mini_tcp::{
+    connections::ConnectionManager,
+    loopback::{LoopbackDevice, LoopbackPeer},
+    packet_loop::packet_loop,
+    tcb::{SocketOptions, TcpStats},
+    tcp::{TcpListener, TcpStream},
+};
+
+/// Bytes sent per run
+const TRANSFER: usize = 8 << 20;
+
+fn spawn_stack(mut dev: LoopbackDevice, mgr: Arc<ConnectionManager>) -> JoinHandle<()> {
+    thread::spawn(move || packet_loop(&mut dev, mgr).unwrap())
+}
+
+// hand every packet one stack sends to the other one
+fn forward(
+    from: Arc<LoopbackPeer>,
+    to: Arc<LoopbackPeer>,
+    stop: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            match from.capture(Some(Duration::from_millis(10))) {
+                Ok(pkt) => to.inject(&pkt).unwrap(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("capture failed: {e}"),
+            }
+        }
+    })
+}
+
+// time sending TRANSFER bytes from one stack to the other, both using `buffer` sized buffers
+fn bulk_transfer(buffer: usize) -> (Duration, TcpStats) {
+    let (client_dev, client_peer) = LoopbackDevice::pair().unwrap();
+    let (server_dev, server_peer) = LoopbackDevice::pair().unwrap();
+    let (client_peer, server_peer) = (Arc::new(client_peer), Arc::new(server_peer));
+    let client = Arc::new(ConnectionManager::new());
+    let server = Arc::new(ConnectionManager::new());
+    let stop = Arc::new(AtomicBool::new(false));
+    let stacks = [
+        spawn_stack(client_dev, client.clone()),
+        spawn_stack(server_dev, server.clone()),
+    ];
+    let forwarders = [
+        forward(client_peer.clone(), server_peer.clone(), stop.clone()),
+        forward(server_peer, client_peer, stop.clone()),
+    ];
+
+    let options = SocketOptions {
+        rx_buffer: buffer,
+        tx_buffer: buffer,
+        ..Default::default()
+    };
+    let server_addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let listener = TcpListener::bind_with_options(server_addr, server.clone(), options).unwrap();
+    let receiver = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        received
+    });
+
+    let client_addr: SocketAddr = "10.0.0.2:0".parse().unwrap();
+    let mut stream =
+        TcpStream::connect_with_options(client_addr, server_addr, client.clone(), options).unwrap();
+    let data = vec![0x5a; TRANSFER];
+    let start = Instant::now();
+    stream.write_all(&data).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(receiver.join().unwrap(), TRANSFER);
+    let elapsed = start.elapsed();
+    let stats = stream.stats();
+
+    // stop forwarding first, a stack that left its loop has closed its device
+    stop.store(true, Ordering::Relaxed);
+    forwarders.into_iter().for_each(|t| t.join().unwrap());
+    client.shutdown();
+    server.shutdown();
+    stacks.into_iter().for_each(|t| t.join().unwrap());
+    (elapsed, stats)
+}
+
+fn main() {
+    for buffer in [4 * 1024, 16 * 1024, 64 * 1024] {
+        let (elapsed, stats) = bulk_transfer(buffer);
+        let mbps = TRANSFER as f64 * 8.0 / elapsed.as_secs_f64() / 1e6;
+        println!(
+            "bulk transfer, {:>6} byte buffers: {} bytes in {:?} ({:.1} Mbit/s), {} fast path ACKs, {} dup ACKs",
+            buffer, TRANSFER, elapsed, mbps, stats.fast_path_acks, stats.dup_acks
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::{VecDeque, vec_deque},
+    ops::{Index, RangeBounds},
+};
+
+/// Byte queue holding at most `capacity` bytes.
+///
+/// Unlike a `VecDeque`, whose capacity is only a hint that grows on demand,
+/// the limit is enforced, so the windows derived from it can be trusted.
+#[derive(Debug)]
+pub struct RingBuffer {
+    buf: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// The most bytes the buffer ever holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Room left before the buffer is full
+    pub fn free(&self) -> usize {
+        self.capacity - self.buf.len()
+    }
+
+    /// Appends as much of `data` as fits, and returns how many bytes that was
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        self.buf.extend(&data[..n]);
+        n
+    }
+
+    /// Drops the first `n` bytes
+    pub fn consume(&mut self, n: usize) {
+        self.buf.drain(..n);
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        self.buf.as_slices()
+    }
+
+    pub fn range<R: RangeBounds<usize>>(&self, range: R) -> vec_deque::Iter<'_, u8> {
+        self.buf.range(range)
+    }
+}
+
+impl Index<usize> for RingBuffer {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.buf[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(buf: &RingBuffer) -> Vec<u8> {
+        let (a, b) = buf.as_slices();
+        [a, b].concat()
+    }
+
+    #[test]
+    fn partial_write_when_nearly_full() {
+        let mut buf = RingBuffer::new(8);
+        assert_eq!(buf.write(&[1, 2, 3, 4, 5]), 5);
+        assert_eq!(buf.free(), 3);
+        assert_eq!(buf.write(&[6, 7, 8, 9, 10]), 3);
+        assert_eq!(buf.len(), 8);
+        assert_eq!(contents(&buf), [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn full_buffer_takes_nothing() {
+        let mut buf = RingBuffer::new(4);
+        assert_eq!(buf.write(&[1, 2, 3, 4]), 4);
+        assert_eq!(buf.free(), 0);
+        assert_eq!(buf.write(&[5]), 0);
+        assert_eq!(buf.len(), buf.capacity());
+        assert_eq!(contents(&buf), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn wraps_around_on_write_and_read() {
+        let mut buf = RingBuffer::new(8);
+        let mut expected = 0u8;
+        let mut next = 0u8;
+        // write and consume in uneven steps, so the data keeps crossing the end of the storage
+        for round in 0..50 {
+            let chunk: Vec<u8> = (0..5)
+                .map(|_| {
+                    next = next.wrapping_add(1);
+                    next
+                })
+                .collect();
+            let written = buf.write(&chunk);
+            next = next.wrapping_sub((chunk.len() - written) as u8);
+            let n = buf.len().min(3 + round % 3);
+            for i in 0..n {
+                expected = expected.wrapping_add(1);
+                assert_eq!(buf[i], expected);
+            }
+            buf.consume(n);
+            assert!(buf.len() <= buf.capacity());
+        }
+        // what is left is still in order, whichever halves it is split in
+        let rest = contents(&buf);
+        for (i, &b) in rest.iter().enumerate() {
+            assert_eq!(b, expected.wrapping_add(i as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let mut buf = RingBuffer::new(8);
+        buf.write(&[1, 2, 3, 4, 5, 6]);
+        buf.consume(4);
+        buf.write(&[7, 8, 9, 10]);
+        // [5, 6, 7, 8, 9, 10], wrapped in the storage
+        assert_eq!(buf.range(1..4).copied().collect::<Vec<_>>(), [6, 7, 8]);
+        assert_eq!(buf[0], 5);
+        assert_eq!(
+            buf.range(..).copied().collect::<Vec<_>>(),
+            [5, 6, 7, 8, 9, 10]
+        );
+        assert_eq!(buf.len(), 6);
+        buf.consume(6);
+        assert!(buf.is_empty());
+        assert_eq!(buf.free(), 8);
+    }
+}
@@ -1,10 +1,22 @@
 use std::{
     collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
-    sync::{Condvar, Mutex},
+    ops::RangeInclusive,
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, TryLockError,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
 };
 
-use crate::tcb::Tcb;
+use crate::tcb::{Tcb, TcpStats};
+
+/// Local ports handed out when none is requested (RFC 6335, section 6)
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 pub enum Tuple {
@@ -92,14 +104,106 @@ pub enum ConnectionType {
     Passive,
 }
 
+/// An established connection, locked independently of all the others
+#[derive(Debug)]
+pub struct Connection {
+    tcb: Mutex<Tcb>,
+    /// Signals there's some data to read
+    read_cvar: Condvar,
+    /// Signals there's some space to write
+    write_cvar: Condvar,
+    /// Set once the connection has been removed from the established map
+    detached: AtomicBool,
+}
+
+impl Connection {
+    pub fn new(tcb: Tcb) -> Self {
+        Self {
+            tcb: Mutex::new(tcb),
+            read_cvar: Condvar::new(),
+            write_cvar: Condvar::new(),
+            detached: AtomicBool::new(false),
+        }
+    }
+
+    pub fn tcb(&self) -> MutexGuard<'_, Tcb> {
+        self.tcb.lock().unwrap()
+    }
+
+    /// Like `tcb`, but `None` instead of blocking while another thread holds it
+    pub fn try_tcb(&self) -> Option<MutexGuard<'_, Tcb>> {
+        match self.tcb.try_lock() {
+            Ok(tcb) => Some(tcb),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(e)) => panic!("{e}"),
+        }
+    }
+
+    pub fn read_cvar(&self) -> &Condvar {
+        &self.read_cvar
+    }
+
+    pub fn write_cvar(&self) -> &Condvar {
+        &self.write_cvar
+    }
+
+    pub fn is_detached(&self) -> bool {
+        self.detached.load(Ordering::Acquire)
+    }
+
+    // mark the connection as gone and wake everyone blocked on it
+    fn detach(&self) {
+        // holding the lock means no waiter can miss the wakeup between its check and wait
+        let _tcb = self.tcb();
+        self.detached.store(true, Ordering::Release);
+        self.read_cvar.notify_all();
+        self.write_cvar.notify_all();
+    }
+}
+
+/// Interrupts the packet loop's poll() when a socket queues work for `on_tick`
+#[derive(Debug)]
+pub struct Waker {
+    rx: UnixStream,
+    tx: UnixStream,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Self> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        Ok(Self { rx, tx })
+    }
+
+    pub fn wake(&self) {
+        // a full socket buffer means a wakeup is already pending
+        let _ = (&self.tx).write(&[1]);
+    }
+
+    /// Consumes the pending wakeups
+    pub fn drain(&self) {
+        let mut buf = [0u8; 64];
+        while let Ok(n) = (&self.rx).read(&mut buf)
+            && n > 0
+        {}
+    }
+}
+
+impl AsFd for Waker {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.rx.as_fd()
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Connections {
     /// Fully established connections
-    established: HashMap<Tuple, Tcb>,
+    established: HashMap<Tuple, Arc<Connection>>,
     /// TCBs bound to ports via bind()
     bound: HashMap<u16, Tcb>,
-    /// Queue of half-established connections (e.g., SYN received)
-    pending: VecDeque<Tcb>,
+    /// Queues of half-established connections (e.g., SYN received), keyed by listening port
+    pending: HashMap<u16, VecDeque<Tcb>>,
 }
 
 impl Connections {
@@ -107,29 +211,37 @@ impl Connections {
         Self {
             established: HashMap::new(),
             bound: HashMap::new(),
-            pending: VecDeque::new(),
+            pending: HashMap::new(),
         }
     }
 
+    /// Finds the half-open connection for `tuple`, searching only the queue of
+    /// the listener on the tuple's local port
     pub fn find_in_pending(&mut self, tuple: Tuple) -> Option<&mut Tcb> {
         self.pending
+            .get_mut(&tuple.local_port())?
             .iter_mut()
-            .find(|tcb| tcb.tuple().unwrap() == tuple)
+            .find(|tcb| tcb.tuple() == Some(tuple))
+    }
+
+    /// Queue of half-established connections of the listener on `port`
+    pub fn pending_on(&mut self, port: u16) -> &mut VecDeque<Tcb> {
+        self.pending.entry(port).or_default()
     }
 
-    pub fn pending_mut(&mut self) -> &mut VecDeque<Tcb> {
+    pub fn pending_mut(&mut self) -> &mut HashMap<u16, VecDeque<Tcb>> {
         &mut self.pending
     }
 
-    pub fn pending(&self) -> &VecDeque<Tcb> {
+    pub fn pending(&self) -> &HashMap<u16, VecDeque<Tcb>> {
         &self.pending
     }
 
-    pub fn established_mut(&mut self) -> &mut HashMap<Tuple, Tcb> {
+    pub fn established_mut(&mut self) -> &mut HashMap<Tuple, Arc<Connection>> {
         &mut self.established
     }
 
-    pub fn established(&self) -> &HashMap<Tuple, Tcb> {
+    pub fn established(&self) -> &HashMap<Tuple, Arc<Connection>> {
         &self.established
     }
 
@@ -140,16 +252,70 @@ impl Connections {
     pub fn bound(&self) -> &HashMap<u16, Tcb> {
         &self.bound
     }
+
+    /// Picks a free port from the ephemeral range for bind(port 0) and active
+    /// opens, `None` once the whole range is in use
+    pub fn ephemeral_port(&self) -> Option<u16> {
+        let in_use = |port: u16| {
+            self.bound.contains_key(&port)
+                || self
+                    .established
+                    .keys()
+                    .any(|tuple| tuple.local_port() == port)
+        };
+        // start at a random offset, so ports aren't reused right away
+        let len = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        let offset = rand::random_range(0..len);
+        (0..len)
+            .map(|i| EPHEMERAL_PORTS.start() + (offset + i) % len)
+            .find(|&port| !in_use(port))
+    }
 }
 
-#[derive(Default, Debug)]
+/// Counters of the whole stack, summed over all connections, gone ones included
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Metrics {
+    /// Segments sent more than once, on RTO or fast retransmit
+    pub retransmits: u64,
+    /// Duplicate ACKs received
+    pub dup_acks: u64,
+    /// Segments queued because they arrived ahead of a hole
+    pub out_of_order: u64,
+    /// Times a peer closed its window
+    pub zero_windows: u64,
+    /// Inbound segments dropped because of a bad TCP checksum
+    pub checksum_errors: u64,
+}
+
+impl Metrics {
+    fn add(&mut self, stats: &TcpStats) {
+        self.retransmits += stats.retransmits;
+        self.dup_acks += stats.dup_acks;
+        self.out_of_order += stats.out_of_order;
+        self.zero_windows += stats.zero_windows;
+    }
+}
+
+#[derive(Debug)]
 pub struct ConnectionManager {
     /// Mutex to protect the connections data structure
     connections: Mutex<Connections>,
     /// Signals when a pending connection becomes established
     pending_cvar: Condvar,
-    /// Signals there's some data to read
-    read_cvar: Condvar,
+    /// Number of inbound segments dropped because of a bad TCP checksum
+    checksum_errors: AtomicU64,
+    /// Counters of the connections that have been removed
+    retired: Mutex<Metrics>,
+    /// Wakes the packet loop when it has to send on behalf of a socket
+    waker: Waker,
+    /// Set by shutdown(), the stack is stopping
+    shut_down: AtomicBool,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConnectionManager {
@@ -157,19 +323,80 @@ impl ConnectionManager {
         Self {
             connections: Mutex::new(Connections::new()),
             pending_cvar: Condvar::new(),
-            read_cvar: Condvar::new(),
+            checksum_errors: AtomicU64::new(0),
+            retired: Mutex::new(Metrics::default()),
+            waker: Waker::new().expect("failed to create the packet loop waker"),
+            shut_down: AtomicBool::new(false),
         }
     }
 
-    pub fn connections(&self) -> std::sync::MutexGuard<Connections> {
+    /// Locks the connection maps. Never take this lock while holding the lock
+    /// of a single connection, it is always acquired first.
+    pub fn connections(&self) -> MutexGuard<'_, Connections> {
         self.connections.lock().unwrap()
     }
 
-    pub fn read_cvar(&self) -> &Condvar {
-        &self.read_cvar
+    /// Removes an established connection and wakes its blocked readers and writers
+    pub fn remove_established(&self, tuple: Tuple) {
+        let conn = self.connections().established_mut().remove(&tuple);
+        if let Some(conn) = conn {
+            // keep its counters, metrics() covers every connection ever made
+            self.retired.lock().unwrap().add(&conn.tcb().stats());
+            conn.detach();
+        }
+    }
+
+    /// Snapshot of the counters of the whole stack
+    pub fn metrics(&self) -> Metrics {
+        let conns = self.connections();
+        let mut metrics = *self.retired.lock().unwrap();
+        for conn in conns.established().values() {
+            metrics.add(&conn.tcb().stats());
+        }
+        metrics.checksum_errors = self.checksum_errors();
+        metrics
+    }
+
+    /// Stops the stack: every connection is reset, blocked socket calls fail,
+    /// and `packet_loop` returns once the resets are sent
+    pub fn shutdown(&self) {
+        let mut conns = self.connections();
+        // set under the lock, so the packet loop sees every connection aborted
+        // once it sees the flag, and no accept() misses it between check and wait
+        self.shut_down.store(true, Ordering::Release);
+        for conn in conns.established().values() {
+            // the flag is set before the TCB lock is taken, so no waiter misses the wakeup
+            conn.tcb().abort();
+            conn.read_cvar.notify_all();
+            conn.write_cvar.notify_all();
+        }
+        // half-open connections are reset too, on_tick reaps them with the others
+        for tcb in conns.pending_mut().values_mut().flatten() {
+            tcb.abort();
+        }
+        self.pending_cvar.notify_all();
+        drop(conns);
+        tracing::info!("shutting down the stack");
+        self.waker.wake();
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
     }
 
     pub fn pending_cvar(&self) -> &Condvar {
         &self.pending_cvar
     }
+
+    pub fn waker(&self) -> &Waker {
+        &self.waker
+    }
+
+    pub fn record_checksum_error(&self) {
+        self.checksum_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn checksum_errors(&self) -> u64 {
+        self.checksum_errors.load(Ordering::Relaxed)
+    }
 }
@@ -1,37 +1,224 @@
-use std::os::fd::{AsFd, BorrowedFd};
+use std::{
+    ffi::CString,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsFd, BorrowedFd, RawFd},
+};
 
 use tun_rs::{DeviceBuilder, SyncDevice};
 
-use crate::TUN_MTU;
+use crate::{
+    TUN_MTU, TcpError,
+    filter::{PacketFilter, Verdict},
+};
+
+/// A link the stack exchanges raw IP packets over
+pub trait PacketDevice {
+    /// Sends one IP packet
+    fn send(&mut self, pkt: &[u8]) -> io::Result<usize>;
+
+    /// Receives one IP packet, `WouldBlock` when none is queued
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Polled by the packet loop, readable once a packet can be received
+    fn as_fd(&self) -> BorrowedFd<'_>;
+
+    fn mtu(&self) -> u16;
+
+    /// Whether packets to `addr` are ours to handle
+    fn is_local(&self, _addr: IpAddr) -> bool {
+        true
+    }
+
+    /// Inspects an inbound packet before the stack processes it
+    fn filter_inbound(&mut self, _pkt: &[u8]) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Inspects an outbound packet before it is sent
+    fn filter_outbound(&mut self, _pkt: &[u8]) -> Verdict {
+        Verdict::Accept
+    }
+}
+
+/// Addressing and MTU of a TUN device that is yet to be created
+#[derive(Clone, Debug)]
+pub struct TunDeviceBuilder {
+    /// IPv4 address and prefix length
+    ipv4: (Ipv4Addr, u8),
+    /// IPv6 address and prefix length
+    ipv6: (Ipv6Addr, u8),
+    /// Addresses the stack answers on, empty to accept packets to any address
+    local_addrs: Vec<IpAddr>,
+    mtu: u16,
+}
+
+impl Default for TunDeviceBuilder {
+    fn default() -> Self {
+        Self {
+            ipv4: (Ipv4Addr::new(10, 0, 0, 1), 24),
+            ipv6: (Ipv6Addr::new(0xfd00, 0xdead, 0xbeef, 0, 0, 0, 0, 1), 64),
+            local_addrs: Vec::new(),
+            mtu: TUN_MTU,
+        }
+    }
+}
+
+impl TunDeviceBuilder {
+    pub fn ipv4(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        self.ipv4 = (addr, prefix_len);
+        self
+    }
+
+    pub fn ipv6(mut self, addr: Ipv6Addr, prefix_len: u8) -> Self {
+        self.ipv6 = (addr, prefix_len);
+        self
+    }
+
+    /// Adds an address the stack answers on, packets to any other address are
+    /// dropped. The interface addresses above belong to the host, not the stack.
+    pub fn local_addr(mut self, addr: IpAddr) -> Self {
+        self.local_addrs.push(addr);
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    pub fn build(self) -> crate::Result<TunDevice> {
+        let dev = DeviceBuilder::new()
+            .ipv4(self.ipv4.0, self.ipv4.1, None)
+            .ipv6(self.ipv6.0, self.ipv6.1)
+            .mtu(self.mtu)
+            .build_sync()
+            .map_err(TcpError::DeviceError)?;
+
+        tracing::info!("TUN device with name '{}' created", dev.name().unwrap());
+
+        let mut tun = TunDevice::from_sync_device(dev, self.mtu)?;
+        tun.addresses = (!self.local_addrs.is_empty()).then_some(self.local_addrs);
+        Ok(tun)
+    }
+}
 
 pub struct TunDevice {
     inner: SyncDevice,
+    /// MTU the device was configured with
+    mtu: u16,
+    /// Addresses the stack answers on.
+    /// `None` when none were configured, e.g. the device was configured elsewhere.
+    addresses: Option<Vec<IpAddr>>,
+    /// Applied to every packet read from the device
+    inbound_filter: Option<PacketFilter>,
+    /// Applied to every packet written to the device
+    outbound_filter: Option<PacketFilter>,
 }
 
 impl TunDevice {
+    /// Creates a device with the default addresses, 10.0.0.1/24 and fd00:dead:beef::1/64
     pub fn new() -> crate::Result<TunDevice> {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> TunDeviceBuilder {
+        TunDeviceBuilder::default()
+    }
+
+    /// Attaches to an existing, already configured TUN device
+    pub fn attach(name: &str) -> crate::Result<TunDevice> {
+        let c_name = CString::new(name)
+            .map_err(|e| TcpError::DeviceError(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        // opening a TUN device by a free name would silently create a new one
+        if unsafe { libc::if_nametoindex(c_name.as_ptr()) } == 0 {
+            return Err(TcpError::DeviceError(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("TUN device '{name}' does not exist"),
+            )));
+        }
         let dev = DeviceBuilder::new()
-            .ipv4("10.0.0.1", 24, None)
-            .ipv6("fd00:dead:beef::1", 64)
-            .mtu(TUN_MTU)
-            .build_sync()?;
+            .name(name)
+            .build_sync()
+            .map_err(TcpError::DeviceError)?;
+        let mtu = dev.mtu().map_err(TcpError::DeviceError)?;
 
-        tracing::info!("TUN device with name '{}' created", dev.name().unwrap());
+        tracing::info!("attached to TUN device '{}'", name);
 
-        dev.set_nonblocking(true)?;
+        TunDevice::from_sync_device(dev, mtu)
+    }
 
-        Ok(TunDevice { inner: dev })
+    /// Wraps an open TUN file descriptor, e.g. one passed in by a parent process
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open TUN device descriptor, owned by the returned device from now on.
+    pub unsafe fn from_fd(fd: RawFd) -> crate::Result<TunDevice> {
+        let dev = unsafe { SyncDevice::from_fd(fd) };
+        let mtu = dev.mtu().map_err(TcpError::DeviceError)?;
+        TunDevice::from_sync_device(dev, mtu)
     }
 
-    pub fn as_fd(&self) -> BorrowedFd<'_> {
-        self.inner.as_fd()
+    fn from_sync_device(dev: SyncDevice, mtu: u16) -> crate::Result<TunDevice> {
+        dev.set_nonblocking(true).map_err(TcpError::DeviceError)?;
+
+        Ok(TunDevice {
+            inner: dev,
+            mtu,
+            addresses: None,
+            inbound_filter: None,
+            outbound_filter: None,
+        })
+    }
+
+    pub fn name(&self) -> io::Result<String> {
+        self.inner.name()
+    }
+
+    pub fn set_inbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.inbound_filter = filter;
     }
 
-    pub fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+    pub fn set_outbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.outbound_filter = filter;
+    }
+}
+
+impl PacketDevice for TunDevice {
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.inner.send(buf)
     }
 
-    pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         self.inner.recv(buf)
     }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    // without configured addresses, everything is accepted
+    fn is_local(&self, addr: IpAddr) -> bool {
+        self.addresses
+            .as_ref()
+            .is_none_or(|addresses| addresses.contains(&addr))
+    }
+
+    fn filter_inbound(&mut self, pkt: &[u8]) -> Verdict {
+        match self.inbound_filter {
+            Some(ref mut filter) => filter(pkt),
+            None => Verdict::Accept,
+        }
+    }
+
+    fn filter_outbound(&mut self, pkt: &[u8]) -> Verdict {
+        match self.outbound_filter {
+            Some(ref mut filter) => filter(pkt),
+            None => Verdict::Accept,
+        }
+    }
 }
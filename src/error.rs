@@ -0,0 +1,70 @@
+use std::{fmt, io};
+
+/// Errors surfaced by the public API of the stack
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TcpError {
+    /// The TUN device could not be created or configured
+    DeviceError(io::Error),
+    /// The requested local port is already bound
+    AddrInUse,
+    /// The connection was reset by the peer
+    ConnectionReset,
+    /// The peer stopped responding
+    TimedOut,
+    /// The peer refused the connection
+    ConnectionRefused,
+    /// The stack was stopped with `ConnectionManager::shutdown`
+    Shutdown,
+    /// Any other I/O failure
+    Io(io::Error),
+}
+
+impl fmt::Display for TcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpError::DeviceError(e) => write!(f, "TUN device error: {e}"),
+            TcpError::AddrInUse => write!(f, "address already in use"),
+            TcpError::ConnectionReset => write!(f, "connection reset by peer"),
+            TcpError::TimedOut => write!(f, "connection timed out"),
+            TcpError::ConnectionRefused => write!(f, "connection refused"),
+            TcpError::Shutdown => write!(f, "the stack has been shut down"),
+            TcpError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TcpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TcpError::DeviceError(e) | TcpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TcpError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::AddrInUse => TcpError::AddrInUse,
+            io::ErrorKind::ConnectionReset => TcpError::ConnectionReset,
+            io::ErrorKind::TimedOut => TcpError::TimedOut,
+            io::ErrorKind::ConnectionRefused => TcpError::ConnectionRefused,
+            _ => TcpError::Io(e),
+        }
+    }
+}
+
+/// Lets socket-facing APIs, which return `io::Result`, propagate stack errors
+impl From<TcpError> for io::Error {
+    fn from(e: TcpError) -> Self {
+        match e {
+            TcpError::DeviceError(e) | TcpError::Io(e) => e,
+            TcpError::AddrInUse => io::Error::from(io::ErrorKind::AddrInUse),
+            TcpError::ConnectionReset => io::Error::from(io::ErrorKind::ConnectionReset),
+            TcpError::TimedOut => io::Error::from(io::ErrorKind::TimedOut),
+            TcpError::ConnectionRefused => io::Error::from(io::ErrorKind::ConnectionRefused),
+            TcpError::Shutdown => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+/// Decision of a [`PacketFilter`] about a single IP packet
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the packet through unchanged
+    Accept,
+    /// Silently discard the packet
+    Drop,
+    /// Pass the given bytes on instead of the original packet
+    Replace(Vec<u8>),
+    /// Hold the packet back for the given time, then pass it on unchanged
+    Delay(Duration),
+}
+
+/// Hook inspecting raw IP packets on their way in or out of the device.
+///
+/// Useful to build middleboxes or to inject loss and corruption in tests.
+pub type PacketFilter = Box<dyn FnMut(&[u8]) -> Verdict + Send>;
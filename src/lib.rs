@@ -1,9 +1,23 @@
+pub mod buffer;
+
 pub mod device;
 
+pub mod error;
+
+pub mod filter;
+
+pub mod loopback;
+
 pub mod packet_loop;
 
+pub mod reassembly;
+
+pub mod seq;
+
 pub mod connections;
 
+pub mod options;
+
 pub mod socket;
 
 pub mod tcb;
@@ -14,6 +28,6 @@ pub mod timers;
 
 const TUN_MTU: u16 = 1500;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub use error::TcpError;
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, TcpError>;
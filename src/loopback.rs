@@ -0,0 +1,128 @@
+use std::{
+    io,
+    net::IpAddr,
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixDatagram,
+    },
+    time::Duration,
+};
+
+use crate::{
+    TUN_MTU,
+    device::PacketDevice,
+    filter::{PacketFilter, Verdict},
+};
+
+/// In-memory device for running the stack without a TUN, e.g. in tests.
+///
+/// Packets sent by the stack are read from the [`LoopbackPeer`], and packets
+/// injected into the peer are received by the stack.
+pub struct LoopbackDevice {
+    sock: UnixDatagram,
+    mtu: u16,
+    /// Addresses the stack answers on, `None` accepts packets to any address
+    addresses: Option<Vec<IpAddr>>,
+    /// Applied to every packet the stack receives
+    inbound_filter: Option<PacketFilter>,
+    /// Applied to every packet the stack sends
+    outbound_filter: Option<PacketFilter>,
+}
+
+/// The far end of a [`LoopbackDevice`], standing in for the network
+pub struct LoopbackPeer {
+    sock: UnixDatagram,
+}
+
+impl LoopbackDevice {
+    /// Creates a device with the default MTU and its peer
+    pub fn pair() -> io::Result<(LoopbackDevice, LoopbackPeer)> {
+        Self::with_mtu(TUN_MTU)
+    }
+
+    pub fn with_mtu(mtu: u16) -> io::Result<(LoopbackDevice, LoopbackPeer)> {
+        // datagrams keep the packet boundaries
+        let (dev, peer) = UnixDatagram::pair()?;
+        dev.set_nonblocking(true)?;
+        Ok((
+            LoopbackDevice {
+                sock: dev,
+                mtu,
+                addresses: None,
+                inbound_filter: None,
+                outbound_filter: None,
+            },
+            LoopbackPeer { sock: peer },
+        ))
+    }
+
+    /// Adds an address the stack answers on, packets to any other address are dropped
+    pub fn with_local_addr(mut self, addr: IpAddr) -> Self {
+        self.addresses.get_or_insert_with(Vec::new).push(addr);
+        self
+    }
+
+    pub fn set_inbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.inbound_filter = filter;
+    }
+
+    pub fn set_outbound_filter(&mut self, filter: Option<PacketFilter>) {
+        self.outbound_filter = filter;
+    }
+}
+
+impl PacketDevice for LoopbackDevice {
+    fn send(&mut self, pkt: &[u8]) -> io::Result<usize> {
+        self.sock.send(pkt)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.recv(buf)
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.sock.as_fd()
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    fn is_local(&self, addr: IpAddr) -> bool {
+        self.addresses
+            .as_ref()
+            .is_none_or(|addresses| addresses.contains(&addr))
+    }
+
+    fn filter_inbound(&mut self, pkt: &[u8]) -> Verdict {
+        match self.inbound_filter {
+            Some(ref mut filter) => filter(pkt),
+            None => Verdict::Accept,
+        }
+    }
+
+    fn filter_outbound(&mut self, pkt: &[u8]) -> Verdict {
+        match self.outbound_filter {
+            Some(ref mut filter) => filter(pkt),
+            None => Verdict::Accept,
+        }
+    }
+}
+
+impl LoopbackPeer {
+    /// Hands a packet to the stack, as if it arrived from the network
+    pub fn inject(&self, pkt: &[u8]) -> io::Result<()> {
+        self.sock.send(pkt)?;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the next packet sent by the stack, `None`
+    /// waits forever. Fails with `WouldBlock` once the timeout passed.
+    pub fn capture(&self, timeout: Option<Duration>) -> io::Result<Vec<u8>> {
+        self.sock.set_read_timeout(timeout)?;
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let n = self.sock.recv(&mut buf)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
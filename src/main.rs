@@ -40,7 +40,13 @@ fn main() -> std::io::Result<()> {
         .with(EnvFilter::from_default_env())
         .init();
 
-    let mut dev = device::TunDevice::new().unwrap();
+    let addr_1: SocketAddr = "10.0.0.9:8080".parse().unwrap();
+    let addr_2: SocketAddr = "[fd00:dead:beef::5]:8081".parse().unwrap();
+    // the stack only answers on the addresses its listeners use
+    let mut dev = device::TunDevice::builder()
+        .local_addr(addr_1.ip())
+        .local_addr(addr_2.ip())
+        .build()?;
     let mgr = Arc::new(ConnectionManager::new());
 
     let mgr_ref = Arc::clone(&mgr);
@@ -51,7 +57,6 @@ fn main() -> std::io::Result<()> {
     });
 
     // launch IPv4 listener on port 8080
-    let addr_1 = "10.0.0.9:8080".parse().unwrap();
     let listener_1 = TcpListener::bind(addr_1, mgr.clone()).unwrap();
     std::thread::spawn(move || {
         while let Ok((stream, addr)) = listener_1.accept() {
@@ -61,7 +66,6 @@ fn main() -> std::io::Result<()> {
     });
 
     // launch IPv6 listener on port 8081
-    let addr_2 = "[fd00:dead:beef::5]:8081".parse().unwrap();
     let listener_2 = TcpListener::bind(addr_2, mgr.clone()).unwrap();
     while let Ok((stream, addr)) = listener_2.accept() {
         println!("accepted a connection: {addr}");
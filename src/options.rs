@@ -0,0 +1,249 @@
+use std::io;
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMP: u8 = 8;
+
+/// Options carried by a TCP segment that the stack understands
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Maximum segment size announced by the peer
+    pub mss: Option<u16>,
+    /// Window scale shift count announced by the peer
+    pub window_scale: Option<u8>,
+    /// Whether the peer allows selective acknowledgments
+    pub sack_permitted: bool,
+    /// TSval and TSecr of the timestamps option
+    pub timestamp: Option<(u32, u32)>,
+    /// Left and right edges of the SACK blocks, at most four
+    pub sack: Vec<(u32, u32)>,
+}
+
+impl TcpOptions {
+    /// Parses the options area of a TCP header.
+    ///
+    /// Options of unknown kind are skipped using their length field, only a
+    /// malformed length makes the whole options area invalid.
+    pub fn parse(mut buf: &[u8]) -> io::Result<TcpOptions> {
+        let mut options = TcpOptions::default();
+        while let Some(&kind) = buf.first() {
+            match kind {
+                KIND_END => break,
+                KIND_NOP => {
+                    buf = &buf[1..];
+                    continue;
+                }
+                _ => {}
+            }
+            // every other option is <kind><length><data>
+            let len = match buf.get(1) {
+                Some(&len) if len >= 2 && len as usize <= buf.len() => len as usize,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed length of TCP option {kind}"),
+                    ));
+                }
+            };
+            let data = &buf[2..len];
+            match (kind, data.len()) {
+                (KIND_MSS, 2) => options.mss = Some(u16::from_be_bytes([data[0], data[1]])),
+                (KIND_WINDOW_SCALE, 1) => options.window_scale = Some(data[0]),
+                (KIND_SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (KIND_SACK, n) if n > 0 && n % 8 == 0 => {
+                    options.sack = data
+                        .chunks_exact(8)
+                        .map(|block| {
+                            let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                            let right =
+                                u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                            (left, right)
+                        })
+                        .collect();
+                }
+                (KIND_TIMESTAMP, 8) => {
+                    let tsval = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                    let tsecr = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                    options.timestamp = Some((tsval, tsecr));
+                }
+                _ => tracing::trace!("skipping TCP option {} of length {}", kind, len),
+            }
+            buf = &buf[len..];
+        }
+        Ok(options)
+    }
+
+    /// TSval and TSecr of an options area holding nothing but the timestamps
+    /// option, laid out as RFC 7323, appendix A suggests: two NOPs, then the option
+    pub fn aligned_timestamp(buf: &[u8]) -> Option<(u32, u32)> {
+        match *buf {
+            [
+                KIND_NOP,
+                KIND_NOP,
+                KIND_TIMESTAMP,
+                10,
+                v0,
+                v1,
+                v2,
+                v3,
+                e0,
+                e1,
+                e2,
+                e3,
+            ] => Some((
+                u32::from_be_bytes([v0, v1, v2, v3]),
+                u32::from_be_bytes([e0, e1, e2, e3]),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Options in the form expected by `etherparse::TcpHeader::set_options`
+    pub fn elements(&self) -> Vec<etherparse::TcpOptionElement> {
+        use etherparse::TcpOptionElement;
+
+        let mut elements = Vec::new();
+        if let Some(mss) = self.mss {
+            elements.push(TcpOptionElement::MaximumSegmentSize(mss));
+        }
+        if let Some(shift) = self.window_scale {
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::WindowScale(shift));
+        }
+        if self.sack_permitted {
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+        }
+        if let Some((tsval, tsecr)) = self.timestamp {
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::Timestamp(tsval, tsecr));
+        }
+        if let Some((&first, rest)) = self.sack.split_first() {
+            let mut blocks = [None; 3];
+            for (block, &edges) in blocks.iter_mut().zip(rest) {
+                *block = Some(edges);
+            }
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::Noop);
+            elements.push(TcpOptionElement::SelectiveAcknowledgement(first, blocks));
+        }
+        elements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS_1460: [u8; 4] = [KIND_MSS, 4, 0x05, 0xb4];
+
+    #[test]
+    fn aligned_timestamp_is_recognized_alone() {
+        let ts = [
+            KIND_NOP,
+            KIND_NOP,
+            KIND_TIMESTAMP,
+            10,
+            0,
+            0,
+            0,
+            7,
+            0,
+            0,
+            1,
+            0,
+        ];
+        assert_eq!(TcpOptions::aligned_timestamp(&ts), Some((7, 256)));
+        // the same option without the padding, or followed by another one, isn't predicted
+        assert_eq!(TcpOptions::aligned_timestamp(&ts[2..]), None);
+        assert_eq!(
+            TcpOptions::aligned_timestamp(&[&ts[..], &[KIND_NOP; 4]].concat()),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_kind_is_skipped() {
+        // kind 30 (MPTCP) with 4 bytes of data, then an MSS
+        let buf = [&[30, 6, 0xde, 0xad, 0xbe, 0xef][..], &MSS_1460].concat();
+        let options = TcpOptions::parse(&buf).unwrap();
+        assert_eq!(options.mss, Some(1460));
+    }
+
+    #[test]
+    fn known_kind_with_unexpected_length_is_skipped() {
+        // a window scale option with 2 bytes of data instead of 1
+        let buf = [&[KIND_WINDOW_SCALE, 4, 7, 7][..], &MSS_1460].concat();
+        let options = TcpOptions::parse(&buf).unwrap();
+        assert_eq!(options.window_scale, None);
+        assert_eq!(options.mss, Some(1460));
+    }
+
+    #[test]
+    fn length_below_two_is_rejected() {
+        for len in [0, 1] {
+            let buf = [KIND_MSS, len, 0x05, 0xb4];
+            let err = TcpOptions::parse(&buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn truncated_option_is_rejected() {
+        // the length claims more bytes than the options area has
+        let err = TcpOptions::parse(&[KIND_TIMESTAMP, 10, 0, 0, 0, 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // the length byte itself is missing
+        let err = TcpOptions::parse(&[KIND_WINDOW_SCALE]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nop_and_end_padding() {
+        let buf = [
+            KIND_NOP,
+            KIND_WINDOW_SCALE,
+            3,
+            7,
+            KIND_NOP,
+            KIND_NOP,
+            KIND_SACK_PERMITTED,
+            2,
+            KIND_END,
+            // garbage after the end of the option list is ignored
+            KIND_MSS,
+            0,
+        ];
+        let options = TcpOptions::parse(&buf).unwrap();
+        assert_eq!(options.window_scale, Some(7));
+        assert!(options.sack_permitted);
+        assert_eq!(options.mss, None);
+    }
+
+    #[test]
+    fn parses_what_elements_writes() {
+        let syn = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            timestamp: Some((1, 2)),
+            ..Default::default()
+        };
+        let data = TcpOptions {
+            timestamp: Some((3, 4)),
+            sack: vec![(100, 200), (300, 400)],
+            ..Default::default()
+        };
+        for options in [syn, data] {
+            let mut hdr = etherparse::TcpHeader::default();
+            hdr.set_options(&options.elements()).unwrap();
+            assert_eq!(TcpOptions::parse(hdr.options.as_slice()).unwrap(), options);
+        }
+    }
+}
@@ -1,68 +1,323 @@
 use std::{
-    collections::hash_map::Entry,
     io::{self},
-    net::{SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsFd, BorrowedFd},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    TUN_MTU,
-    connections::{ConnectionManager, Tuple, TupleV4, TupleV6},
-    device,
+    connections::{Connection, ConnectionManager, Tuple, TupleV4, TupleV6},
+    device::PacketDevice,
+    filter::Verdict,
+    reassembly::Reassembler,
+    tcb::{State, Tcb},
 };
 
+/// Pause before retrying a device read that failed with ENOBUFS
+const RECV_BACKOFF: Duration = Duration::from_millis(5);
+
+/// How soon the loop looks again at a connection an application thread held
+const BUSY_RETRY: Duration = Duration::from_millis(1);
+
+/// Packets a filter held back with `Verdict::Delay`
+#[derive(Default)]
+struct DelayQueue(Vec<(Instant, Vec<u8>)>);
+
+impl DelayQueue {
+    fn push(&mut self, delay: Duration, pkt: &[u8]) {
+        self.0.push((Instant::now() + delay, pkt.to_vec()));
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.0.iter().map(|&(due, _)| due).min()
+    }
+
+    // the packets that are due, in the order they became due
+    fn take_due(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        self.0.sort_by_key(|&(due, _)| due);
+        let n = self.0.partition_point(|&(due, _)| due <= now);
+        self.0.drain(..n).map(|(_, pkt)| pkt).collect()
+    }
+}
+
+/// The device as the stack sees it: what the stack sends passes the device's
+/// outbound filter first, and the packets it delays are held here
+struct FilteredDevice<'a, D> {
+    dev: &'a mut D,
+    delayed: DelayQueue,
+}
+
+impl<D: PacketDevice> FilteredDevice<'_, D> {
+    // send the delayed packets that are due, past the filter that delayed them
+    fn send_due(&mut self) {
+        for pkt in self.delayed.take_due() {
+            if let Err(e) = self.dev.send(&pkt) {
+                tracing::warn!("failed to send a delayed packet: {}", e);
+            }
+        }
+    }
+}
+
+impl<D: PacketDevice> PacketDevice for FilteredDevice<'_, D> {
+    fn send(&mut self, pkt: &[u8]) -> io::Result<usize> {
+        match self.dev.filter_outbound(pkt) {
+            Verdict::Accept => self.dev.send(pkt),
+            // pretend the packet went out, it's lost on the wire
+            Verdict::Drop => Ok(pkt.len()),
+            Verdict::Replace(pkt) => self.dev.send(&pkt),
+            Verdict::Delay(delay) => {
+                self.delayed.push(delay, pkt);
+                Ok(pkt.len())
+            }
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.dev.recv(buf)
+    }
+
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.dev.as_fd()
+    }
+
+    fn mtu(&self) -> u16 {
+        self.dev.mtu()
+    }
+
+    fn is_local(&self, addr: IpAddr) -> bool {
+        self.dev.is_local(addr)
+    }
+
+    fn filter_inbound(&mut self, pkt: &[u8]) -> Verdict {
+        self.dev.filter_inbound(pkt)
+    }
+}
+
 #[tracing::instrument(skip(dev, mgr))]
-pub fn packet_loop(dev: &mut device::TunDevice, mgr: Arc<ConnectionManager>) -> io::Result<()> {
-    let mut buf = [0u8; TUN_MTU as usize];
+pub fn packet_loop(dev: &mut impl PacketDevice, mgr: Arc<ConnectionManager>) -> io::Result<()> {
+    let dev = &mut FilteredDevice {
+        dev,
+        delayed: DelayQueue::default(),
+    };
+    let mut delayed_inbound = DelayQueue::default();
+    let mut buf = vec![0u8; dev.mtu() as usize];
+    let mut fragments = Reassembler::new();
     loop {
+        // packets a filter held back go on before anything new
+        dev.send_due();
+        for pkt in delayed_inbound.take_due() {
+            process_packet(dev, mgr.clone(), &mut fragments, &pkt)?;
+        }
+        // read before the tick, which then sends the resets queued by shutdown()
+        let shutting_down = mgr.is_shut_down();
+        on_tick(dev, &mgr);
+        if shutting_down {
+            tracing::info!("stack shut down, leaving the packet loop");
+            return Ok(());
+        }
         use nix::poll::{PollFd, PollFlags, PollTimeout};
-        let mut pfd = [PollFd::new(dev.as_fd(), PollFlags::POLLIN)];
-        let nready = nix::poll::poll(&mut pfd[..], PollTimeout::from(10u16)).unwrap();
-        // check timers and tx buffer if there is no incoming packet
-        if nready == 0 {
-            let mut conns = mgr.connections();
-            conns.established_mut().retain(|tuple, tcb| {
-                if let Err(e) = tcb.on_tick(dev) {
-                    tracing::warn!("failed for {:?}: {}", tuple, e);
-                    return true; // do not drop, even if send failed 
-                }
-                if tcb.is_closed() {
-                    tracing::debug!("removing tuple: {:?}", tuple);
-                    false
-                } else {
-                    true
-                }
-            });
+        // sleep until a segment arrives, a timer or delayed packet is due, or a socket has work
+        let deadline = [
+            next_deadline(&mgr),
+            dev.delayed.next_due(),
+            delayed_inbound.next_due(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let timeout = match deadline {
+            Some(deadline) => {
+                // round up, waking a little early would only spin until the deadline
+                let millis = deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_micros()
+                    .div_ceil(1000);
+                PollTimeout::try_from(millis).unwrap_or(PollTimeout::MAX)
+            }
+            None => PollTimeout::NONE,
+        };
+        let mut pfd = [
+            PollFd::new(dev.as_fd(), PollFlags::POLLIN),
+            PollFd::new(mgr.waker().as_fd(), PollFlags::POLLIN),
+        ];
+        match nix::poll::poll(&mut pfd[..], timeout) {
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let readable = |pfd: &PollFd| pfd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN));
+        let (packet_ready, woken) = (readable(&pfd[0]), readable(&pfd[1]));
+        if woken {
+            mgr.waker().drain();
+        }
+        if !packet_ready {
             continue;
         }
         match dev.recv(&mut buf) {
             Ok(n) => {
                 let pkt = &buf[0..n];
-                process_packet(dev, mgr.clone(), pkt)?;
+                match dev.filter_inbound(pkt) {
+                    Verdict::Accept => process_packet(dev, mgr.clone(), &mut fragments, pkt)?,
+                    Verdict::Drop => tracing::trace!("inbound packet dropped by filter"),
+                    Verdict::Replace(pkt) => {
+                        process_packet(dev, mgr.clone(), &mut fragments, &pkt)?
+                    }
+                    Verdict::Delay(delay) => delayed_inbound.push(delay, pkt),
+                }
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(e) => return Err(e),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                tracing::debug!("device read interrupted, retrying");
+                continue;
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                // the device is temporarily out of buffer space, give it a moment
+                tracing::warn!("device read failed: {}, backing off", e);
+                std::thread::sleep(RECV_BACKOFF);
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("fatal device read error: {}", e);
+                return Err(e);
+            }
         }
     }
 }
 
+// run on_tick for the connections that have a timer due or data to send
+fn on_tick(dev: &mut impl PacketDevice, mgr: &ConnectionManager) {
+    // work due right away reports the instant next_deadline() ran, so compare
+    // against a clock read after it, never one taken before
+    let is_due = |tcb: &Tcb| {
+        tcb.next_deadline()
+            .is_some_and(|deadline| deadline <= Instant::now())
+    };
+    // snapshot the connections, so the map isn't locked while they are serviced
+    let mut order: Vec<(u8, Tuple, Arc<Connection>)> = mgr
+        .connections()
+        .established()
+        .iter()
+        .filter_map(|(tuple, conn)| {
+            // one an application thread holds waits for a later round, blocking on
+            // it would stall every other connection
+            let tcb = conn.try_tcb()?;
+            is_due(&tcb).then(|| (tcb.priority(), *tuple, conn.clone()))
+        })
+        .collect();
+    // service higher priority connections first, so they get the device before others
+    order.sort_by_key(|&(priority, _, _)| std::cmp::Reverse(priority));
+    for (_, tuple, conn) in order {
+        let _span = tracing::debug_span!("conn", ?tuple).entered();
+        let Some(mut tcb) = conn.try_tcb() else {
+            continue;
+        };
+        let keep = match tcb.on_tick(dev) {
+            Ok(()) => !tcb.is_closed(),
+            Err(e) => {
+                tracing::warn!("failed for {:?}: {}", tuple, e);
+                // do not drop, even if send failed, unless the peer is gone
+                let gone = e.kind() == io::ErrorKind::TimedOut;
+                if gone {
+                    tcb.set_error(e.kind());
+                }
+                !gone
+            }
+        };
+        drop(tcb);
+        if !keep {
+            tracing::debug!("removing tuple: {:?}", tuple);
+            mgr.remove_established(tuple);
+        }
+    }
+    // half-open connections retransmit their SYN-ACK
+    let mut conns = mgr.connections();
+    for queue in conns.pending_mut().values_mut() {
+        queue.retain_mut(|tcb| {
+            if !is_due(tcb) {
+                return true;
+            }
+            let _span = tracing::debug_span!("conn", tuple = ?tcb.tuple()).entered();
+            match tcb.on_tick(dev) {
+                Ok(()) => !tcb.is_closed(),
+                Err(e) => {
+                    tracing::warn!("failed for pending {:?}: {}", tcb.tuple(), e);
+                    e.kind() != io::ErrorKind::TimedOut
+                }
+            }
+        });
+    }
+}
+
+// the earliest instant any connection, established or half-open, has work for on_tick
+fn next_deadline(mgr: &ConnectionManager) -> Option<Instant> {
+    let conns = mgr.connections();
+    let established = conns
+        .established()
+        .values()
+        .filter_map(|conn| match conn.try_tcb() {
+            Some(tcb) => tcb.next_deadline(),
+            // its timers may come due while it is held, look again shortly
+            None => Some(Instant::now() + BUSY_RETRY),
+        });
+    let pending = conns
+        .pending()
+        .values()
+        .flatten()
+        .filter_map(Tcb::next_deadline);
+    established.chain(pending).min()
+}
+
 fn process_packet(
-    dev: &mut device::TunDevice,
+    dev: &mut impl PacketDevice,
     mgr: Arc<ConnectionManager>,
+    fragments: &mut Reassembler,
     pkt: &[u8],
 ) -> io::Result<()> {
     if let Ok(ipv4_hdr) = etherparse::Ipv4HeaderSlice::from_slice(pkt) {
         let src = ipv4_hdr.source_addr();
         let dest = ipv4_hdr.destination_addr();
+        if !dev.is_local(dest.into()) {
+            tracing::debug!("dropping IPv4 packet for foreign address {}", dest);
+            return Ok(());
+        }
         // Reject everything not TCP for now
         if ipv4_hdr.protocol() != etherparse::IpNumber::TCP {
             return Ok(());
         }
+        if ipv4_hdr.to_header().calc_header_checksum() != ipv4_hdr.header_checksum() {
+            tracing::warn!(
+                "dropping IPv4 packet with a bad header checksum from {}",
+                src
+            );
+            return Ok(());
+        }
+        // a fragment only carries a part of the segment, parse it once the datagram is whole
+        if ipv4_hdr.is_fragmenting_payload() {
+            let header_len = ipv4_hdr.slice().len();
+            let Some(payload) = pkt.get(header_len..ipv4_hdr.total_len() as usize) else {
+                tracing::warn!("dropping IPv4 fragment with a total length past the packet end");
+                return Ok(());
+            };
+            if let Some(datagram) = fragments.push(&ipv4_hdr, payload) {
+                return process_packet(dev, mgr, fragments, &datagram);
+            }
+            return Ok(());
+        }
         let tcp_offset: usize = (ipv4_hdr.ihl() << 2).into(); // IPv4 header is 4 words long
         match etherparse::TcpHeaderSlice::from_slice(&pkt[tcp_offset..]) {
             Ok(tcph) => {
                 let data_offset: usize = (tcph.data_offset() << 2).into();
-                let payload = &pkt[tcp_offset + data_offset..];
+                let Some(payload) = pkt.get(tcp_offset + data_offset..) else {
+                    tracing::warn!("dropping TCP segment with data offset past the packet end");
+                    return Ok(());
+                };
+                if tcph.calc_checksum_ipv4(&ipv4_hdr, payload).ok() != Some(tcph.checksum()) {
+                    tracing::debug!("dropping TCP segment with a bad checksum");
+                    mgr.record_checksum_error();
+                    return Ok(());
+                }
                 /* uniquely represents a connection */
                 let tuple = Tuple::V4(TupleV4 {
                     local: SocketAddrV4::new(dest, tcph.destination_port()),
@@ -75,15 +330,46 @@ fn process_packet(
     } else if let Ok(ipv6_hdr) = etherparse::Ipv6HeaderSlice::from_slice(pkt) {
         let src = ipv6_hdr.source_addr();
         let dest = ipv6_hdr.destination_addr();
+        if !dev.is_local(dest.into()) {
+            tracing::debug!("dropping IPv6 packet for foreign address {}", dest);
+            return Ok(());
+        }
+        // walk the extension headers (hop-by-hop, routing, ...) to the upper-layer header
+        let header_len = ipv6_hdr.slice().len();
+        let (exts, next_header, rest) = match etherparse::Ipv6ExtensionsSlice::from_slice(
+            ipv6_hdr.next_header(),
+            &pkt[header_len..],
+        ) {
+            Ok(exts) => exts,
+            Err(e) => {
+                tracing::warn!(
+                    "dropping IPv6 packet with malformed extension headers: {:?}",
+                    e
+                );
+                return Ok(());
+            }
+        };
         // Reject everything not TCP for now
-        if ipv6_hdr.next_header() != etherparse::IpNumber::TCP {
+        if next_header != etherparse::IpNumber::TCP {
+            return Ok(());
+        }
+        if exts.is_fragmenting_payload() {
+            tracing::warn!("dropping fragmented IPv6 packet from {}", src);
             return Ok(());
         }
-        let tcp_offset: usize = ipv6_hdr.slice().len();
+        let tcp_offset: usize = pkt.len() - rest.len();
         match etherparse::TcpHeaderSlice::from_slice(&pkt[tcp_offset..]) {
             Ok(tcph) => {
                 let data_offset: usize = (tcph.data_offset() << 2).into();
-                let payload = &pkt[tcp_offset + data_offset..];
+                let Some(payload) = pkt.get(tcp_offset + data_offset..) else {
+                    tracing::warn!("dropping TCP segment with data offset past the packet end");
+                    return Ok(());
+                };
+                if tcph.calc_checksum_ipv6(&ipv6_hdr, payload).ok() != Some(tcph.checksum()) {
+                    tracing::debug!("dropping TCP segment with a bad checksum");
+                    mgr.record_checksum_error();
+                    return Ok(());
+                }
                 /* uniquely represents a connection */
                 let tuple = Tuple::V6(TupleV6 {
                     local: SocketAddrV6::new(dest, tcph.destination_port(), 0, 0),
@@ -99,42 +385,130 @@ fn process_packet(
 }
 
 fn process_tcp_slice(
-    dev: &mut device::TunDevice,
+    dev: &mut impl PacketDevice,
     mgr: Arc<ConnectionManager>,
     tcph: etherparse::TcpHeaderSlice,
     payload: &[u8],
     tuple: Tuple,
 ) -> io::Result<()> {
-    let mut conns = mgr.connections();
-
-    match conns.established_mut().entry(tuple) {
-        Entry::Vacant(_) => {
-            // it's likely, the connection was already initialized:
-            if let Some(client) = conns.find_in_pending(tuple) {
-                client.on_segment(dev, &tcph, payload, mgr.read_cvar())?;
-                mgr.pending_cvar().notify_all(); // notify accept() about an established connection
-                return Ok(());
-            }
-            // connection wasn't initialized, try to establish one
-            if let Some(listener) = conns.bound_mut().get_mut(&tuple.local_port()) {
-                if let Some(client) = listener.try_establish(dev, &tcph, tuple)? {
-                    conns.pending_mut().push_back(client);
-                }
-            }
+    let _span = tracing::debug_span!("conn", ?tuple).entered();
+    // only hold the map lock for the lookup, the segment is processed under the connection's lock
+    let conn = mgr.connections().established().get(&tuple).cloned();
+    if let Some(conn) = conn {
+        let mut tcb = conn.tcb();
+        // a new SYN beyond the end of a connection in TIME-WAIT may reopen the tuple (RFC 1122, 4.2.2.13)
+        if tcph.syn() && !tcph.ack() && tcb.accepts_reopening_syn(tcph.sequence_number()) {
+            tracing::info!("new SYN for {:?} in TIME-WAIT, reopening", tuple);
+            drop(tcb);
+            mgr.remove_established(tuple);
+            return process_tcp_slice(dev, mgr, tcph, payload, tuple);
         }
-        Entry::Occupied(mut o) => {
-            if let Err(error) = o.get_mut().on_segment(dev, &tcph, payload, mgr.read_cvar()) {
-                match error.kind() {
-                    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {
-                        tracing::info!("removing a connection: {:?}", &tuple);
-                        conns.established_mut().remove(&tuple);
-                        mgr.read_cvar().notify_all();
-                    }
-                    _ => {}
-                }
+        let result = tcb.on_segment(dev, &tcph, payload, conn.read_cvar(), conn.write_cvar());
+        if let Err(error) = result {
+            let kind = error.kind();
+            if matches!(
+                kind,
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+            ) {
+                tracing::info!("removing a connection: {:?}", &tuple);
+                // readers and writers woken by the removal report the error
+                tcb.set_error(kind);
+                drop(tcb);
+                mgr.remove_established(tuple);
             }
         }
+        return Ok(());
+    }
+
+    let mut conns = mgr.connections();
+    // it's likely, the connection was already initialized:
+    if let Some(client) = conns.find_in_pending(tuple) {
+        // nobody reads or writes a connection before it is accepted, accept() is the one waiting
+        let result = client.on_segment(dev, &tcph, payload, mgr.pending_cvar(), mgr.pending_cvar());
+        // a reset half-open connection falls back to LISTEN or CLOSED, it is never accepted
+        if result.is_err() || matches!(client.state(), State::Listen | State::Closed) {
+            tracing::info!("half-open connection {:?} was reset, dropping it", tuple);
+            conns
+                .pending_on(tuple.local_port())
+                .retain(|tcb| tcb.tuple() != Some(tuple));
+        }
+        mgr.pending_cvar().notify_all(); // notify accept() about an established connection
+        return Ok(());
+    }
+    // connection wasn't initialized, try to establish one
+    let port = tuple.local_port();
+    let queued = conns.pending().get(&port).map_or(0, |queue| queue.len());
+    let Some(listener) = conns.bound_mut().get_mut(&port).filter(|listener| {
+        listener.state() == State::Listen && listener.listens_on(tuple.local_ip())
+    }) else {
+        // nobody listens on the port, it is closed
+        tracing::debug!("segment for closed port {}, resetting", port);
+        return Tcb::reject(dev, tuple, &tcph, payload);
+    };
+    if tcph.syn() && queued >= listener.backlog() {
+        tracing::warn!("backlog of port {} is full, dropping SYN", port);
+        return Ok(());
+    }
+    if let Some(client) = listener.try_establish(dev, &tcph, tuple)? {
+        conns.pending_on(port).push_back(client);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{loopback::LoopbackDevice, tcp::TcpListener};
+
+    // the formatted log output, shared with the subscriber
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn state_transitions_are_logged_in_the_connection_span() {
+        let (mut dev, _peer) = LoopbackDevice::pair().unwrap();
+        let mgr = Arc::new(ConnectionManager::new());
+        let _listener = TcpListener::bind("10.0.0.1:8080".parse().unwrap(), mgr.clone()).unwrap();
+        let mut syn = Vec::new();
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+            .tcp(40000, 8080, 1000, 1024)
+            .syn()
+            .write(&mut syn, &[])
+            .unwrap();
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            process_packet(&mut dev, mgr.clone(), &mut Reassembler::new(), &syn).unwrap();
+        });
+
+        // the SYN moves the new connection to SYN-RECEIVED, logged under its tuple
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let transition = logs
+            .lines()
+            .find(|line| line.contains("state transition"))
+            .expect("no state transition logged");
+        assert!(transition.contains("conn{tuple="), "{transition}");
+        assert!(transition.contains("10.0.0.2:40000"), "{transition}");
+        assert!(transition.contains("to=SynRcvd"), "{transition}");
+    }
+}
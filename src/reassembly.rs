@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use etherparse::{IpFragOffset, Ipv4Header, Ipv4HeaderSlice};
+
+/// Time an incomplete datagram waits for its missing fragments (RFC 1122, 3.3.2)
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum number of datagrams reassembled at once, fragments of others are dropped
+const MAX_DATAGRAMS: usize = 64;
+/// Maximum bytes held for one datagram, no IPv4 datagram is longer
+const MAX_DATAGRAM_BYTES: usize = u16::MAX as usize;
+/// Maximum bytes held across all datagrams, fragments past it are dropped
+const MAX_HELD_BYTES: usize = 1 << 20;
+
+/// Identifies the fragments of one datagram (RFC 791)
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+struct FragmentKey {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    id: u16,
+    protocol: u8,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    /// Arrival of the first fragment, the datagram is dropped once it times out
+    started_at: Instant,
+    /// Header of the fragment at offset 0, the reassembled datagram reuses it
+    header: Option<Ipv4Header>,
+    /// Payload length, known once the last fragment arrived
+    total_len: Option<usize>,
+    /// Fragment payloads keyed by their byte offset
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Bytes held in `fragments`, overlaps counted twice
+    held: usize,
+}
+
+impl Datagram {
+    // concatenate the payloads, once they cover the whole datagram without holes
+    fn assemble(&self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        let mut payload = Vec::with_capacity(total_len);
+        for (&offset, data) in &self.fragments {
+            if offset > payload.len() {
+                return None;
+            }
+            // overlapping bytes were already taken from an earlier fragment
+            let overlap = payload.len() - offset;
+            if overlap < data.len() {
+                payload.extend_from_slice(&data[overlap..]);
+            }
+        }
+        if payload.len() < total_len {
+            return None;
+        }
+        payload.truncate(total_len);
+        Some(payload)
+    }
+}
+
+/// Collects IPv4 fragments until their datagram is complete
+#[derive(Default, Debug)]
+pub struct Reassembler {
+    datagrams: HashMap<FragmentKey, Datagram>,
+    /// Bytes held across all datagrams
+    held: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            datagrams: HashMap::new(),
+            held: 0,
+        }
+    }
+
+    /// Adds a fragment, and returns the whole datagram, header included, once
+    /// its last missing fragment has arrived
+    pub fn push(&mut self, hdr: &Ipv4HeaderSlice, payload: &[u8]) -> Option<Vec<u8>> {
+        let held = &mut self.held;
+        self.datagrams.retain(|_, datagram| {
+            let alive = datagram.started_at.elapsed() < REASSEMBLY_TIMEOUT;
+            if !alive {
+                *held -= datagram.held;
+            }
+            alive
+        });
+        let key = FragmentKey {
+            src: hdr.source_addr(),
+            dst: hdr.destination_addr(),
+            id: hdr.identification(),
+            protocol: hdr.protocol().0,
+        };
+        if !self.datagrams.contains_key(&key) && self.datagrams.len() >= MAX_DATAGRAMS {
+            tracing::warn!("too many datagrams in reassembly, dropping fragment");
+            return None;
+        }
+        let offset = hdr.fragments_offset().byte_offset() as usize;
+        if offset + payload.len() > MAX_DATAGRAM_BYTES {
+            tracing::warn!("fragment ends past the maximum datagram length, dropping");
+            return None;
+        }
+        if self.held + payload.len() > MAX_HELD_BYTES {
+            tracing::warn!("too many bytes held in reassembly, dropping fragment");
+            return None;
+        }
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            started_at: Instant::now(),
+            header: None,
+            total_len: None,
+            fragments: BTreeMap::new(),
+            held: 0,
+        });
+        // overlapping fragments can't make a datagram hold more than its maximum length
+        if datagram.held + payload.len() > MAX_DATAGRAM_BYTES {
+            tracing::warn!("datagram holds too many overlapping fragments, dropping it");
+            self.held -= datagram.held;
+            self.datagrams.remove(&key);
+            return None;
+        }
+        if offset == 0 {
+            datagram.header = Some(hdr.to_header());
+        }
+        if !hdr.more_fragments() {
+            datagram.total_len = Some(offset + payload.len());
+        }
+        datagram.held += payload.len();
+        self.held += payload.len();
+        if let Some(old) = datagram.fragments.insert(offset, payload.to_vec()) {
+            datagram.held -= old.len();
+            self.held -= old.len();
+        }
+
+        let payload = datagram.assemble()?;
+        let datagram = self.datagrams.remove(&key)?;
+        self.held -= datagram.held;
+        let mut header = datagram.header?;
+        header.more_fragments = false;
+        header.fragment_offset = IpFragOffset::ZERO;
+        if header.set_payload_len(payload.len()).is_err() {
+            tracing::warn!("reassembled datagram exceeds the maximum IPv4 length, dropping");
+            return None;
+        }
+        let mut pkt = Vec::with_capacity(header.header_len() + payload.len());
+        // the checksum is recalculated on write
+        header.write(&mut pkt).ok()?;
+        pkt.extend_from_slice(&payload);
+        tracing::debug!("reassembled a datagram of {} bytes", pkt.len());
+        Some(pkt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::IpNumber;
+
+    use super::*;
+
+    // an IPv4 fragment of datagram `id`, `offset` must be a multiple of 8
+    fn fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+        let mut hdr = Ipv4Header::new(
+            payload.len() as u16,
+            64,
+            IpNumber::TCP,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+        )
+        .unwrap();
+        hdr.identification = id;
+        hdr.more_fragments = more;
+        hdr.fragment_offset = IpFragOffset::try_new((offset / 8) as u16).unwrap();
+        let mut pkt = hdr.to_bytes().to_vec();
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    fn push(reassembler: &mut Reassembler, pkt: &[u8]) -> Option<Vec<u8>> {
+        let hdr = Ipv4HeaderSlice::from_slice(pkt).unwrap();
+        reassembler.push(&hdr, &pkt[hdr.slice().len()..])
+    }
+
+    // the payload of a reassembled datagram, checking it no longer looks fragmented
+    fn payload_of(pkt: &[u8]) -> &[u8] {
+        let hdr = Ipv4HeaderSlice::from_slice(pkt).unwrap();
+        assert!(!hdr.is_fragmenting_payload());
+        assert_eq!(hdr.total_len() as usize, pkt.len());
+        &pkt[hdr.slice().len()..]
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn two_fragments_are_delivered_whole() {
+        let data = data(48);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(
+            push(&mut reassembler, &fragment(1, 0, true, &data[..24])),
+            None
+        );
+        let pkt = push(&mut reassembler, &fragment(1, 24, false, &data[24..])).unwrap();
+        assert_eq!(payload_of(&pkt), data);
+        assert_eq!(reassembler.held, 0);
+    }
+
+    #[test]
+    fn out_of_order_and_overlapping_fragments() {
+        let data = data(64);
+        let mut reassembler = Reassembler::new();
+        // the last fragment first, then two that overlap by 8 bytes
+        assert_eq!(
+            push(&mut reassembler, &fragment(2, 48, false, &data[48..])),
+            None
+        );
+        assert_eq!(
+            push(&mut reassembler, &fragment(2, 16, true, &data[16..48])),
+            None
+        );
+        let pkt = push(&mut reassembler, &fragment(2, 0, true, &data[..24])).unwrap();
+        assert_eq!(payload_of(&pkt), data);
+        assert_eq!(reassembler.held, 0);
+    }
+
+    #[test]
+    fn datagrams_are_kept_apart() {
+        let data = data(32);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(
+            push(&mut reassembler, &fragment(3, 0, true, &data[..16])),
+            None
+        );
+        assert_eq!(
+            push(&mut reassembler, &fragment(4, 16, false, &data[16..])),
+            None
+        );
+        assert_eq!(reassembler.held, 32);
+    }
+
+    #[test]
+    fn fragment_past_the_maximum_datagram_length_is_dropped() {
+        let mut reassembler = Reassembler::new();
+        let pkt = fragment(5, MAX_DATAGRAM_BYTES - 7, false, &[0; 8]);
+        assert_eq!(push(&mut reassembler, &pkt), None);
+        assert_eq!(reassembler.held, 0);
+        assert!(reassembler.datagrams.is_empty());
+    }
+
+    #[test]
+    fn overlapping_fragments_cannot_exceed_the_datagram_cap() {
+        let chunk = vec![0; 24 * 1024];
+        let mut reassembler = Reassembler::new();
+        assert_eq!(push(&mut reassembler, &fragment(6, 0, true, &chunk)), None);
+        assert_eq!(push(&mut reassembler, &fragment(6, 8, true, &chunk)), None);
+        // a third overlap would hold more than any datagram can be long
+        assert_eq!(push(&mut reassembler, &fragment(6, 16, true, &chunk)), None);
+        assert_eq!(reassembler.held, 0);
+        assert!(reassembler.datagrams.is_empty());
+    }
+
+    #[test]
+    fn total_held_bytes_are_capped() {
+        let chunk = vec![0; 32 * 1024];
+        let mut reassembler = Reassembler::new();
+        let fits = MAX_HELD_BYTES / chunk.len();
+        for id in 0..fits as u16 {
+            push(&mut reassembler, &fragment(id, 0, true, &chunk));
+        }
+        assert_eq!(reassembler.held, fits * chunk.len());
+        // a new datagram doesn't fit anymore
+        push(&mut reassembler, &fragment(fits as u16, 0, true, &chunk));
+        assert_eq!(reassembler.held, fits * chunk.len());
+        assert_eq!(reassembler.datagrams.len(), fits);
+    }
+}
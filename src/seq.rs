@@ -0,0 +1,86 @@
+//! Comparisons of sequence numbers modulo 2^32.
+//!
+//! Sequence numbers wrap around, so `a` precedes `b` when the signed distance
+//! `a - b` is negative (RFC 1323, section 4.2).
+
+/// `a < b`
+pub fn lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// `a =< b`
+pub fn leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// `a > b`
+pub fn gt(a: u32, b: u32) -> bool {
+    lt(b, a)
+}
+
+/// `a >= b`
+pub fn geq(a: u32, b: u32) -> bool {
+    leq(b, a)
+}
+
+/// `low =< x < high`
+pub fn between(low: u32, x: u32, high: u32) -> bool {
+    x.wrapping_sub(low) < high.wrapping_sub(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordering_across_the_wrap() {
+        assert!(lt(0xFFFF_FFFF, 0));
+        assert!(gt(0, 0xFFFF_FFFF));
+        assert!(leq(0xFFFF_FFF0, 0x10));
+        assert!(geq(0x10, 0xFFFF_FFF0));
+        assert!(!lt(0, 0xFFFF_FFFF));
+        assert!(leq(0xFFFF_FFFF, 0xFFFF_FFFF));
+        assert!(!lt(0xFFFF_FFFF, 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn half_the_space_apart() {
+        // up to 2^31 - 1 ahead is later
+        let a = 0xFFFF_FF00u32;
+        let b = a.wrapping_add((1 << 31) - 1);
+        assert!(lt(a, b) && !lt(b, a));
+        // exactly 2^31 apart is ambiguous: each number precedes the other
+        let c = a.wrapping_add(1 << 31);
+        assert!(lt(a, c) && lt(c, a));
+        assert!(gt(a, c) && gt(c, a));
+    }
+
+    #[test]
+    fn between_across_the_wrap() {
+        assert!(between(0xFFFF_FFF0, 0xFFFF_FFF0, 0x10));
+        assert!(between(0xFFFF_FFF0, 0xFFFF_FFFF, 0x10));
+        assert!(between(0xFFFF_FFF0, 0, 0x10));
+        assert!(between(0xFFFF_FFF0, 0xF, 0x10));
+        assert!(!between(0xFFFF_FFF0, 0x10, 0x10));
+        assert!(!between(0xFFFF_FFF0, 0xFFFF_FFEF, 0x10));
+        // an empty range holds nothing
+        assert!(!between(5, 5, 5));
+    }
+
+    #[test]
+    fn random_offsets_agree_with_plain_ordering() {
+        // shifting both numbers by the same amount never changes their order,
+        // as long as they are less than 2^31 apart
+        for _ in 0..10_000 {
+            let base = rand::random::<u32>();
+            let d = rand::random::<u32>() >> 1;
+            let (a, b) = (base, base.wrapping_add(d));
+            assert_eq!(lt(a, b), d > 0);
+            assert!(leq(a, b));
+            assert_eq!(gt(b, a), d > 0);
+            assert!(geq(b, a));
+            let x = base.wrapping_add(rand::random::<u32>() >> 1);
+            assert_eq!(between(a, x, b), x.wrapping_sub(base) < d);
+        }
+    }
+}
@@ -1,13 +1,26 @@
-use std::{collections::hash_map::Entry, io, net::SocketAddr, sync::Arc};
+use std::{
+    collections::hash_map::Entry,
+    io,
+    net::{Shutdown, SocketAddr},
+    sync::{Arc, MutexGuard},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    connections::{ConnectionManager, Tuple, TupleV4, TupleV6},
-    tcb::Tcb,
+    TcpError,
+    connections::{Connection, ConnectionManager, Tuple, TupleV4, TupleV6},
+    tcb::{Keepalive, SocketOptions, State, Tcb, TcpStats},
 };
 
 pub struct Socket {
     mgr: Arc<ConnectionManager>,
     tuple: Tuple,
+    /// Whether blocking operations return `WouldBlock` instead of waiting
+    nonblocking: bool,
+    /// The established connection, for sockets returned by accept()
+    conn: Option<Arc<Connection>>,
+    /// How long write and flush may block, `None` waits forever
+    write_timeout: Option<Duration>,
 }
 
 impl Socket {
@@ -16,7 +29,13 @@ impl Socket {
             SocketAddr::V4(_) => Tuple::V4(TupleV4::default()),
             SocketAddr::V6(_) => Tuple::V6(TupleV6::default()),
         };
-        Socket { mgr, tuple }
+        Socket {
+            mgr,
+            tuple,
+            nonblocking: false,
+            conn: None,
+            write_timeout: None,
+        }
     }
 
     pub fn remote_addr(&self) -> SocketAddr {
@@ -26,6 +45,15 @@ impl Socket {
         }
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.tuple.local_ip()
+    }
+
+    /// Whether the connection still exists
+    pub fn is_connected(&self) -> bool {
+        self.conn.as_ref().is_some_and(|conn| !conn.is_detached())
+    }
+
     pub fn local_port(&self) -> u16 {
         match self.tuple {
             Tuple::V4(tuple_v4) => tuple_v4.local.port(),
@@ -33,13 +61,180 @@ impl Socket {
         }
     }
 
-    pub fn connect(_addr: SocketAddr) -> io::Result<Socket> {
-        unimplemented!()
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    pub fn established_at(&self) -> Option<Instant> {
+        self.conn
+            .as_ref()
+            .and_then(|conn| conn.tcb().established_at())
+    }
+
+    /// State of the connection, `Closed` for sockets that aren't connected
+    pub fn state(&self) -> State {
+        self.conn
+            .as_ref()
+            .map_or(State::Closed, |conn| conn.tcb().state())
+    }
+
+    pub fn has_urgent_data(&self) -> bool {
+        self.conn
+            .as_ref()
+            .is_some_and(|conn| conn.tcb().has_urgent_data())
+    }
+
+    pub fn stats(&self) -> TcpStats {
+        self.conn
+            .as_ref()
+            .map_or_else(TcpStats::default, |conn| conn.tcb().stats())
+    }
+
+    pub fn set_priority(&mut self, priority: u8) -> io::Result<()> {
+        match &self.conn {
+            Some(conn) if !conn.is_detached() => {
+                conn.tcb().set_priority(priority);
+                Ok(())
+            }
+            _ => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        if let Some(conn) = &self.conn {
+            conn.tcb().set_keepalive(keepalive);
+            self.mgr.waker().wake(); // the keepalive deadline changed
+        }
+    }
+
+    // blocking calls fail once the stack has been shut down
+    fn check_shut_down(&self) -> io::Result<()> {
+        if self.mgr.is_shut_down() {
+            return Err(TcpError::Shutdown.into());
+        }
+        Ok(())
+    }
+
+    // a connection that was reset or timed out fails reads and writes
+    fn check_error(tcb: &Tcb) -> io::Result<()> {
+        match tcb.error() {
+            Some(kind) => Err(io::Error::from(kind)),
+            None => Ok(()),
+        }
     }
 
-    pub fn bind(&mut self, addr: SocketAddr) -> io::Result<()> {
-        let tcb = Tcb::new(addr);
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        if let Some(conn) = &self.conn {
+            conn.tcb().set_idle_timeout(timeout);
+            self.mgr.waker().wake(); // the idle deadline changed
+        }
+    }
+
+    /// Actively opens a connection from `local`, port 0 picks an ephemeral
+    /// port, to `remote` and blocks until the handshake is complete
+    pub fn connect(
+        local: SocketAddr,
+        remote: SocketAddr,
+        mgr: Arc<ConnectionManager>,
+        options: SocketOptions,
+    ) -> io::Result<Socket> {
+        if local.is_ipv4() != remote.is_ipv4() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local and remote address families differ",
+            ));
+        }
+        let (tuple, conn) = {
+            let mut conns = mgr.connections();
+            if mgr.is_shut_down() {
+                return Err(TcpError::Shutdown.into());
+            }
+            let local = if local.port() == 0 {
+                let port = conns.ephemeral_port().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::AddrInUse, "no ephemeral port available")
+                })?;
+                SocketAddr::new(local.ip(), port)
+            } else {
+                local
+            };
+            let tuple = Tuple::new(local, remote);
+            if conns.established().contains_key(&tuple) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "connection already exists",
+                ));
+            }
+            let mut tcb = Tcb::with_options(local, options);
+            tcb.connect(tuple);
+            let conn = Arc::new(Connection::new(tcb));
+            conns.established_mut().insert(tuple, conn.clone());
+            tracing::info!("connecting to {}", remote);
+            (tuple, conn)
+        };
+        mgr.waker().wake(); // on_tick sends the SYN
+        let sock = Socket {
+            mgr,
+            tuple,
+            nonblocking: false,
+            conn: Some(conn),
+            write_timeout: None,
+        };
+        sock.wait_connected()?;
+        Ok(sock)
+    }
+
+    // block until the handshake of an active open completes or fails
+    fn wait_connected(&self) -> io::Result<()> {
+        let Some(conn) = &self.conn else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+        let mut tcb = conn.tcb();
+        while tcb.is_connecting() {
+            self.check_shut_down()?;
+            Self::check_error(&tcb)?;
+            if conn.is_detached() {
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+            }
+            tcb = conn.write_cvar().wait(tcb).unwrap();
+        }
+        self.check_shut_down()?;
+        Self::check_error(&tcb)?;
+        if conn.is_detached() || tcb.is_closed() {
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+        }
+        Ok(())
+    }
+
+    /// Binds a listening TCB to `addr`, the connections it accepts use `options`
+    pub fn bind(&mut self, addr: SocketAddr, options: SocketOptions) -> io::Result<()> {
         let mut conns = self.mgr.connections();
+        self.check_shut_down()?;
+        // port 0 asks for any free port
+        let addr = if addr.port() == 0 {
+            let port = conns.ephemeral_port().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrInUse, "no ephemeral port available")
+            })?;
+            SocketAddr::new(addr.ip(), port)
+        } else {
+            addr
+        };
+        // like SO_REUSEADDR: without it, connections left on the port keep it taken
+        if !options.reuse_addr
+            && conns
+                .established()
+                .keys()
+                .any(|tuple| tuple.local_port() == addr.port())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "port is still used by connections",
+            ));
+        }
+        let tcb = Tcb::with_options(addr, options);
         match conns.bound_mut().entry(addr.port()) {
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
@@ -72,6 +267,16 @@ impl Socket {
         Ok(())
     }
 
+    // release the port, connections that haven't been accepted yet are dropped with it
+    pub fn unbind(&mut self) {
+        let port = self.local_port();
+        let mut conns = self.mgr.connections();
+        if conns.bound_mut().remove(&port).is_some() {
+            tracing::info!("unbound port {}", port);
+        }
+        conns.pending_mut().remove(&port);
+    }
+
     pub fn listen(&mut self) {
         let port = self.local_port();
         let mut conns = self.mgr.connections();
@@ -81,18 +286,98 @@ impl Socket {
         }
     }
 
+    pub fn set_initial_rcv_wnd(&mut self, wnd: u16) {
+        let port = self.local_port();
+        let mut conns = self.mgr.connections();
+        if let Some(tcb) = conns.bound_mut().get_mut(&port) {
+            tcb.set_initial_rcv_wnd(wnd);
+        }
+    }
+
+    /// TTL of the connection, or of the connections a listener accepts
+    pub fn ttl(&self) -> u8 {
+        if let Some(conn) = &self.conn {
+            return conn.tcb().ttl();
+        }
+        let conns = self.mgr.connections();
+        conns
+            .bound()
+            .get(&self.local_port())
+            .map_or_else(|| SocketOptions::default().ttl, Tcb::ttl)
+    }
+
+    /// TOS of the connection, or of the connections a listener accepts
+    pub fn tos(&self) -> u8 {
+        if let Some(conn) = &self.conn {
+            return conn.tcb().tos();
+        }
+        let conns = self.mgr.connections();
+        conns
+            .bound()
+            .get(&self.local_port())
+            .map_or_else(|| SocketOptions::default().tos, Tcb::tos)
+    }
+
+    pub fn set_tos(&mut self, tos: u8) {
+        if let Some(conn) = &self.conn {
+            conn.tcb().set_tos(tos);
+            return;
+        }
+        let port = self.local_port();
+        let mut conns = self.mgr.connections();
+        if let Some(tcb) = conns.bound_mut().get_mut(&port) {
+            tcb.set_tos(tos);
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        if let Some(conn) = &self.conn {
+            conn.tcb().set_ttl(ttl);
+            return;
+        }
+        let port = self.local_port();
+        let mut conns = self.mgr.connections();
+        if let Some(tcb) = conns.bound_mut().get_mut(&port) {
+            tcb.set_ttl(ttl);
+        }
+    }
+
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        let port = self.local_port();
+        let mut conns = self.mgr.connections();
+        if let Some(tcb) = conns.bound_mut().get_mut(&port) {
+            tcb.set_handshake_timeout(timeout);
+        }
+    }
+
     pub fn accept(&self) -> io::Result<Socket> {
+        let port = self.local_port();
         loop {
             let mut conns = self.mgr.connections();
-            while conns.pending_mut().is_empty() {
+            // only connections made to our own port, once their handshake is complete
+            while !conns.pending_on(port).iter().any(Tcb::is_ready_to_accept) {
+                self.check_shut_down()?;
+                if self.nonblocking {
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
                 conns = self.mgr.pending_cvar().wait(conns).unwrap();
             }
-            if let Some(tcb) = conns.pending_mut().pop_front() {
+            // what's still queued was reset by the shutdown
+            self.check_shut_down()?;
+            let queue = conns.pending_on(port);
+            if let Some(mut tcb) = queue
+                .iter()
+                .position(Tcb::is_ready_to_accept)
+                .and_then(|i| queue.remove(i))
+            {
+                tcb.set_accepted();
                 let tuple = match tcb.remote_addr() {
                     Some(remote_addr) => Tuple::new(tcb.listen_addr(), remote_addr),
                     None => panic!("shouldn't have happened!"),
                 };
-                conns.established_mut().insert(tuple, tcb);
+                let conn = Arc::new(Connection::new(tcb));
+                conns.established_mut().insert(tuple, conn.clone());
+                self.mgr.waker().wake(); // have the packet loop schedule the new connection
                 tracing::info!("accepted a connection from: {}", tuple.remote_port());
                 tracing::debug!(
                     "number of established connections: {}",
@@ -101,41 +386,141 @@ impl Socket {
                 return Ok(Self {
                     mgr: self.mgr.clone(),
                     tuple,
+                    nonblocking: false,
+                    conn: Some(conn),
+                    write_timeout: None,
                 });
             }
         }
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut conns = self.mgr.connections();
+        self.recv(buf, false)
+    }
+
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf, true)
+    }
+
+    // wait for received data and read it, or only copy it when peeking
+    fn recv(&mut self, buf: &mut [u8], peek: bool) -> io::Result<usize> {
+        let Some(conn) = &self.conn else {
+            return Ok(0);
+        };
+        let mut tcb = conn.tcb();
         loop {
-            match conns.established_mut().get_mut(&self.tuple) {
-                Some(tcb) => {
-                    if !tcb.rx_is_empty() {
-                        return tcb.read(buf);
-                    }
-                    if tcb.is_closing() {
-                        return Ok(0);
-                    }
-                    conns = self.mgr.read_cvar().wait(conns).unwrap();
+            self.check_shut_down()?;
+            Self::check_error(&tcb)?;
+            if !tcb.rx_is_empty() {
+                if peek {
+                    return tcb.peek(buf);
                 }
-                None => return Ok(0),
+                let n = tcb.read(buf)?;
+                // reading may have opened the window enough to announce it
+                self.mgr.waker().wake();
+                return Ok(n);
             }
+            // EOF once the peer's FIN was consumed, whether or not we closed first
+            if tcb.fin_received() || tcb.is_closed() || tcb.is_read_shutdown() || conn.is_detached()
+            {
+                return Ok(0);
+            }
+            if self.nonblocking {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            tcb = conn.read_cvar().wait(tcb).unwrap();
         }
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut conns = self.mgr.connections();
-        match conns.established_mut().get_mut(&self.tuple) {
-            Some(tcb) => tcb.write(buf),
-            None => Ok(0),
+        let Some(conn) = &self.conn else {
+            return Ok(0);
+        };
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let mut tcb = conn.tcb();
+        loop {
+            self.check_shut_down()?;
+            Self::check_error(&tcb)?;
+            if conn.is_detached() {
+                return Ok(0);
+            }
+            let n = tcb.write(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.mgr.waker().wake(); // on_tick sends the queued data
+                return Ok(n);
+            }
+            if self.nonblocking {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            // tx_buffer is full, wait for an ACK to free some space
+            tcb = Self::wait_acked(conn, tcb, deadline)?;
         }
     }
 
-    pub fn close(&self) {
-        let mut conns = self.mgr.connections();
-        if let Some(tcb) = conns.established_mut().get_mut(&self.tuple) {
-            tcb.init_closing()
+    // wait until everything queued has been sent and acknowledged
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(conn) = &self.conn else {
+            return Ok(());
+        };
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let mut tcb = conn.tcb();
+        while !tcb.tx_is_empty() || tcb.bytes_in_flight() > 0 {
+            self.check_shut_down()?;
+            Self::check_error(&tcb)?;
+            if conn.is_detached() {
+                // the connection is gone, the rest of the data never will be acknowledged
+                return Err(io::Error::from(io::ErrorKind::NotConnected));
+            }
+            if self.nonblocking {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            tcb = Self::wait_acked(conn, tcb, deadline)?;
         }
+        Ok(())
+    }
+
+    // block on the write cvar, which is notified when an ACK advances SND.UNA
+    fn wait_acked<'a>(
+        conn: &'a Connection,
+        tcb: MutexGuard<'a, Tcb>,
+        deadline: Option<Instant>,
+    ) -> io::Result<MutexGuard<'a, Tcb>> {
+        let Some(deadline) = deadline else {
+            return Ok(conn.write_cvar().wait(tcb).unwrap());
+        };
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        Ok(conn.write_cvar().wait_timeout(tcb, timeout).unwrap().0)
+    }
+
+    // drop the connection with a RST, the packet loop sends it and reaps the TCB
+    pub fn abort(&self) -> io::Result<()> {
+        let Some(conn) = self.conn.as_ref().filter(|conn| !conn.is_detached()) else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+        conn.tcb().abort();
+        // blocked readers and writers see the connection closed
+        conn.read_cvar().notify_all();
+        conn.write_cvar().notify_all();
+        self.mgr.waker().wake();
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let Some(conn) = self.conn.as_ref().filter(|conn| !conn.is_detached()) else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+        let mut tcb = conn.tcb();
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            tcb.shutdown_read();
+            conn.read_cvar().notify_all(); // blocked readers see EOF
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            tcb.init_closing();
+            self.mgr.waker().wake(); // on_tick sends the FIN
+        }
+        Ok(())
     }
 }
@@ -1,24 +1,91 @@
 use std::{
-    collections::VecDeque,
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
     io::{self},
     net::SocketAddr,
     sync::Condvar,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
     TUN_MTU,
+    buffer::RingBuffer,
     connections::{ConnectionType, Tuple},
-    device,
-    timers::TimerManager,
+    device::PacketDevice,
+    options::TcpOptions,
+    seq,
+    timers::{RTOEntry, TimerManager},
 };
 
-/// TTL for IPv4
+thread_local! {
+    /// Segments are serialized into this buffer, so sending doesn't allocate once it has grown
+    static SEND_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Default IPv4 TTL and IPv6 hop limit
 const HOP_LIMIT: u8 = 64;
 
-/// Limit for send's
+/// ECN bits of the TOS / traffic class byte (RFC 3168)
+const ECN_MASK: u8 = 0b11;
+
+/// Default capacity of the send and receive buffers, in bytes
 const QUEUE_LIMIT: usize = 1024;
 
+/// Default Maximum Segment Lifetime, TIME-WAIT lasts twice as long
+const MSL: Duration = Duration::from_secs(30);
+
+/// Upper bound of the zero-window probe interval
+const PERSIST_MAX: Duration = Duration::from_secs(60);
+
+/// Default lower bound of the retransmission timeout, also its value before the first RTT sample
+const RTO_MIN: Duration = Duration::from_millis(200);
+
+/// Default upper bound of the retransmission timeout
+const RTO_MAX: Duration = Duration::from_secs(60);
+
+/// Default number of retransmissions of unacknowledged data before the connection is aborted
+const MAX_RETRANSMITS: u32 = 15;
+
+/// Default time an ACK for in-order data may be held back
+const ACK_DELAY: Duration = Duration::from_millis(200);
+
+/// Duplicate ACKs that trigger a fast retransmit
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// MSS assumed when the peer doesn't announce one (RFC 9293, 3.7.1)
+const DEFAULT_MSS: u16 = 536;
+
+/// Size of a TCP header without options
+const TCP_HEADER_LEN: u16 = 20;
+
+/// Length of the timestamps option and its two NOPs of padding, taken from
+/// the payload of every segment once negotiated
+const TIMESTAMP_OPTION_LEN: u16 = 12;
+
+/// Largest window scale shift count allowed (RFC 7323, 2.3)
+const MAX_WINDOW_SHIFT: u8 = 14;
+
+/// Default time between unanswered keepalive probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+
+/// Default number of unanswered keepalive probes before the peer is declared dead
+const KEEPALIVE_PROBES: u32 = 9;
+
+/// Default number of connections a listener queues until they are accepted
+const BACKLOG: usize = 128;
+
+/// Default time a half-open connection may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of SYN retransmissions before an active open gives up
+const SYN_RETRIES: u32 = 5;
+
+// IW = min(4*SMSS, max(2*SMSS, 4380 bytes)), RFC 3390
+fn initial_cwnd(smss: u32) -> u32 {
+    (4 * smss).min((2 * smss).max(4380))
+}
+
 #[derive(Default, Clone, Copy, Debug)]
 pub struct TcpFlags {
     syn: bool,
@@ -27,8 +94,115 @@ pub struct TcpFlags {
     rst: bool,
 }
 
+impl TcpFlags {
+    /// Sequence space taken by the control flags, SYN and FIN occupy one number each
+    pub fn seq_len(&self) -> u32 {
+        self.syn as u32 + self.fin as u32
+    }
+}
+
+/// A snapshot of the counters and the congestion state of a connection
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpStats {
+    /// Segments sent more than once, on RTO or fast retransmit
+    pub retransmits: u64,
+    /// Duplicate ACKs received
+    pub dup_acks: u64,
+    /// ACKs handled by the header prediction fast path
+    pub fast_path_acks: u64,
+    /// Segments queued because they arrived ahead of a hole
+    pub out_of_order: u64,
+    /// Times the peer closed its window
+    pub zero_windows: u64,
+    /// Bytes the application queued for sending
+    pub bytes_sent: u64,
+    /// Bytes received in order from the peer
+    pub bytes_received: u64,
+    /// Smoothed round-trip time, once measured
+    pub srtt: Option<Duration>,
+    /// Current retransmission timeout
+    pub rto: Duration,
+    /// Congestion window in bytes
+    pub cwnd: u32,
+    /// Window advertised to the peer
+    pub rcv_wnd: u32,
+    /// Window advertised by the peer
+    pub snd_wnd: u32,
+}
+
+/// Keepalive parameters of a connection
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    /// Idle time before the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Number of unanswered probes before the peer is declared dead
+    pub probes: u32,
+}
+
+impl Keepalive {
+    /// Probes after `idle` with the default interval and probe count
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: KEEPALIVE_INTERVAL,
+            probes: KEEPALIVE_PROBES,
+        }
+    }
+}
+
+/// Per-socket settings, a listener hands them down to the connections it accepts
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    /// Capacity of the receive buffer, it bounds the advertised window
+    pub rx_buffer: usize,
+    /// Capacity of the send buffer
+    pub tx_buffer: usize,
+    /// IPv4 TTL or IPv6 hop limit of the packets sent
+    pub ttl: u8,
+    /// IPv4 TOS or IPv6 traffic class of the packets sent, only the DSCP bits are used
+    pub tos: u8,
+    /// Lower bound of the retransmission timeout, also its value before the first RTT sample
+    pub rto_min: Duration,
+    /// Upper bound of the retransmission timeout, also caps the backoff
+    pub rto_max: Duration,
+    /// Unanswered retransmissions before the connection times out, like `TCP_USER_TIMEOUT`
+    pub max_retransmits: u32,
+    /// Maximum Segment Lifetime, TIME-WAIT lasts twice as long
+    pub msl: Duration,
+    /// SYN retransmissions of an active open before connect() times out
+    pub max_syn_retries: u32,
+    /// How long an ACK may be held back, `Duration::ZERO` disables delayed ACKs
+    pub ack_delay: Duration,
+    /// Half-open connections a listener queues, further SYNs are dropped
+    pub backlog: usize,
+    /// Whether a listener may bind a port that connections, e.g. in TIME-WAIT,
+    /// still use (SO_REUSEADDR)
+    pub reuse_addr: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            rx_buffer: QUEUE_LIMIT,
+            tx_buffer: QUEUE_LIMIT,
+            ttl: HOP_LIMIT,
+            tos: 0,
+            rto_min: RTO_MIN,
+            rto_max: RTO_MAX,
+            max_retransmits: MAX_RETRANSMITS,
+            msl: MSL,
+            max_syn_retries: SYN_RETRIES,
+            ack_delay: ACK_DELAY,
+            backlog: BACKLOG,
+            reuse_addr: false,
+        }
+    }
+}
+
 /// The state of a TCB
-#[derive(Hash, Eq, PartialEq, Debug)]
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
 pub enum State {
     Listen,
     SynSent,
@@ -43,6 +217,38 @@ pub enum State {
     Closed,
 }
 
+/// One-line dump of the sequence variables, windows and timers of a TCB, see `Tcb::snapshot`
+pub struct Snapshot<'a>(&'a Tcb);
+
+impl fmt::Display for Snapshot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tcb = self.0;
+        let now = Instant::now();
+        let remaining = |at: Option<Instant>| at.map(|at| at.saturating_duration_since(now));
+        write!(
+            f,
+            "{:?} SND.UNA={} SND.NXT={} SND.WND={} RCV.NXT={} RCV.WND={} in_flight={} \
+             cwnd={} ssthresh={} rto={:?} timers: rto={} (next {:?}) persist={:?} \
+             delayed_ack={:?} time_wait={:?}",
+            tcb.state,
+            tcb.snd_una,
+            tcb.snd_nxt,
+            tcb.snd_wnd,
+            tcb.rcv_nxt,
+            tcb.rcv_wnd,
+            tcb.bytes_in_flight(),
+            tcb.cwnd,
+            tcb.ssthresh,
+            tcb.rto,
+            tcb.timers.rto_count(),
+            remaining(tcb.timers.next_rto_expiry()),
+            remaining(tcb.timers.persist_expiry()),
+            remaining(tcb.timers.delayed_ack_expiry()),
+            remaining(tcb.timers.time_wait_expiry()),
+        )
+    }
+}
+
 /// Transmission Control Block
 #[derive(Debug)]
 pub struct Tcb {
@@ -54,12 +260,18 @@ pub struct Tcb {
     remote_addr: Option<SocketAddr>,
     /// Determines whether it's a client or a server
     connection_type: ConnectionType,
+    /// Set once accept() handed the connection to the application
+    accepted: bool,
     /// 4-Tuple
     tuple: Option<Tuple>,
     /// Transmit buffer
-    tx_buffer: VecDeque<u8>,
+    tx_buffer: RingBuffer,
     /// Receive buffer
-    rx_buffer: VecDeque<u8>,
+    rx_buffer: RingBuffer,
+    /// Segments received ahead of RCV.NXT, keyed by sequence number
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    /// Sequence number of a FIN received behind a hole, consumed once the hole is filled
+    out_of_order_fin: Option<u32>,
     /// Initial seq number of sender
     iss: u32,
     /// Oldest unacknowledged byte sent
@@ -67,7 +279,7 @@ pub struct Tcb {
     /// Next seq number to be sent
     snd_nxt: u32,
     /// Available buffer space for sending
-    snd_wnd: u16,
+    snd_wnd: u32,
     /// Last segment’s sequence number for window update
     snd_wl1: u32,
     /// Last segment’s acknowledgment number for window update
@@ -76,24 +288,141 @@ pub struct Tcb {
     irs: u32,
     /// Next byte expected from sender
     rcv_nxt: u32,
+    /// Receive urgent pointer, one past the last urgent byte (RFC 6093)
+    rcv_up: Option<u32>,
     /// Available buffer space for receiving
-    rcv_wnd: u16,
+    rcv_wnd: u32,
+    /// Both sides agreed on window scaling, or we offered it in our SYN
+    window_scaling: bool,
+    /// Shift count applied to windows advertised by the peer
+    snd_wnd_shift: u8,
+    /// Shift count applied to the windows we advertise
+    rcv_wnd_shift: u8,
+    /// Both sides agreed on the timestamps option, or we offered it in our SYN
+    timestamps: bool,
+    /// Latest TSval received from the peer (TS.Recent)
+    ts_recent: u32,
+    /// Origin of our timestamp clock
+    ts_base: Instant,
+    /// Random offset added to our timestamp clock
+    ts_offset: u32,
+    /// Both sides agreed on selective acknowledgments, or we offered them in our SYN
+    sack_permitted: bool,
+    /// Ranges above SND.UNA the peer reported as received
+    sacked: Vec<(u32, u32)>,
+    /// Longest time an ACK may be delayed, zero acknowledges every segment right away
+    ack_delay: Duration,
+    /// Bytes received since the last ACK was sent
+    unacked_bytes: usize,
     /// RTO in (ms)
     rto: Duration,
+    /// Smoothed round-trip time, None until the first measurement
+    srtt: Option<Duration>,
+    /// Round-trip time variation
+    rttvar: Duration,
+    /// Lower bound of the RTO
+    rto_min: Duration,
+    /// Upper bound of the RTO, also caps the backoff
+    rto_max: Duration,
+    /// Number of retransmissions since new data was last acknowledged
+    retransmits: u32,
+    /// Total number of retransmitted segments
+    segments_retransmitted: u64,
+    /// Total number of duplicate ACKs received
+    dup_acks_received: u64,
+    /// Total number of ACKs handled by the header prediction fast path
+    fast_path_acks: u64,
+    /// Total number of segments queued out of order
+    out_of_order_segments: u64,
+    /// Total number of times the peer advertised a zero window
+    zero_window_events: u64,
+    /// Total number of bytes queued by the application
+    bytes_sent: u64,
+    /// Total number of bytes delivered in order
+    bytes_received: u64,
+    /// Number of retransmissions after which the connection is aborted
+    max_retransmits: u32,
+    /// Consecutive duplicate ACKs received
+    dup_acks: u32,
+    /// MTU of the device the connection runs over
+    mtu: u16,
+    /// Largest segment payload sent to the peer
+    mss: u16,
+    /// Congestion window in bytes
+    cwnd: u32,
+    /// Slow start threshold in bytes
+    ssthresh: u32,
+    /// SND.NXT at the time fast recovery was entered, None outside of it
+    recover: Option<u32>,
     /// Timers for the current connection
     timers: TimerManager,
+    /// Keepalive parameters, disabled when None
+    keepalive: Option<Keepalive>,
+    /// Number of keepalive probes sent since the peer was last heard from
+    keepalive_probes: u32,
+    /// Last time a valid segment was received
+    last_recv: Instant,
+    /// Time after which a connection without any traffic or application calls is reset
+    idle_timeout: Option<Duration>,
+    /// Last time a segment was received, or the application read or wrote
+    last_activity: Instant,
+    /// Time the connection entered ESTABLISHED
+    established_at: Option<Instant>,
+    /// Window advertised during the handshake instead of the buffer-derived one
+    initial_rcv_wnd: Option<u16>,
+    /// Connections a listener queues, half-open or not yet accepted
+    backlog: usize,
+    /// Time after which a connection still in SYN-RECEIVED is reset
+    handshake_timeout: Duration,
+    /// Time the SYN that opened this connection was received
+    syn_rcvd_at: Option<Instant>,
+    /// Number of times the SYN has been retransmitted
+    syn_retries: u32,
+    /// Number of SYN retransmissions before the handshake times out
+    max_syn_retries: u32,
+    /// Sequence number occupied by our FIN, once it has been sent
+    fin_seq: Option<u32>,
+    /// Scheduling priority of the send queue, higher is serviced first
+    priority: u8,
+    /// The application shut down reading, received text is discarded
+    rx_shutdown: bool,
+    /// The application freed receive space the peer should hear about
+    window_update_pending: bool,
+    /// The application aborted the connection, the RST is still to be sent
+    rst_pending: bool,
+    /// The application opened the connection, the SYN is still to be sent
+    syn_pending: bool,
+    /// Why the connection failed, e.g. reset by the peer, reported to the application
+    error: Option<io::ErrorKind>,
+    /// Interval between zero-window probes, backed off like the RTO
+    persist_interval: Duration,
+    /// Maximum Segment Lifetime
+    msl: Duration,
+    /// IPv4 TTL or IPv6 hop limit of the packets sent
+    ttl: u8,
+    /// IPv4 TOS or IPv6 traffic class of the packets sent
+    tos: u8,
+    /// Settings this TCB was created with, inherited by accepted connections
+    options: SocketOptions,
 }
 
 impl Tcb {
     pub fn new(addr: SocketAddr) -> Self {
+        Self::with_options(addr, SocketOptions::default())
+    }
+
+    pub fn with_options(addr: SocketAddr, options: SocketOptions) -> Self {
         Self {
             state: State::Closed,
             local_addr: addr,
             remote_addr: None,
             connection_type: ConnectionType::Passive,
+            accepted: false,
             tuple: None,
-            tx_buffer: VecDeque::with_capacity(QUEUE_LIMIT),
-            rx_buffer: VecDeque::with_capacity(QUEUE_LIMIT),
+            tx_buffer: RingBuffer::new(options.tx_buffer),
+            rx_buffer: RingBuffer::new(options.rx_buffer),
+            out_of_order: BTreeMap::new(),
+            out_of_order_fin: None,
             iss: rand::random::<u32>(),
             snd_una: 0,
             snd_nxt: 0,
@@ -102,9 +431,65 @@ impl Tcb {
             snd_wl2: 0,
             irs: 0,
             rcv_nxt: 0,
-            rcv_wnd: 4096,
-            rto: Duration::from_millis(200),
+            rcv_up: None,
+            // the whole receive buffer is free
+            rcv_wnd: options.rx_buffer as u32,
+            window_scaling: false,
+            snd_wnd_shift: 0,
+            rcv_wnd_shift: 0,
+            timestamps: false,
+            ts_recent: 0,
+            ts_base: Instant::now(),
+            ts_offset: rand::random::<u32>(),
+            sack_permitted: false,
+            sacked: Vec::new(),
+            ack_delay: options.ack_delay,
+            unacked_bytes: 0,
+            rto: options.rto_min.min(options.rto_max),
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto_min: options.rto_min,
+            rto_max: options.rto_max,
+            retransmits: 0,
+            segments_retransmitted: 0,
+            dup_acks_received: 0,
+            fast_path_acks: 0,
+            out_of_order_segments: 0,
+            zero_window_events: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            max_retransmits: options.max_retransmits,
+            dup_acks: 0,
+            mtu: TUN_MTU,
+            mss: DEFAULT_MSS,
+            cwnd: initial_cwnd(DEFAULT_MSS as u32),
+            ssthresh: u32::MAX,
+            recover: None,
             timers: TimerManager::new(),
+            keepalive: None,
+            keepalive_probes: 0,
+            last_recv: Instant::now(),
+            idle_timeout: None,
+            last_activity: Instant::now(),
+            established_at: None,
+            initial_rcv_wnd: None,
+            backlog: options.backlog,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            syn_rcvd_at: None,
+            syn_retries: 0,
+            max_syn_retries: options.max_syn_retries,
+            fin_seq: None,
+            priority: 0,
+            rx_shutdown: false,
+            window_update_pending: false,
+            rst_pending: false,
+            syn_pending: false,
+            error: None,
+            persist_interval: Duration::from_millis(200),
+            msl: options.msl,
+            ttl: options.ttl,
+            tos: options.tos & !ECN_MASK,
+            options,
         }
     }
 
@@ -112,6 +497,13 @@ impl Tcb {
         self.local_addr
     }
 
+    /// Whether a listener bound to `listen_addr` takes connections made to `addr`,
+    /// the wildcard (0.0.0.0 or ::) matches any address of its family
+    pub fn listens_on(&self, addr: SocketAddr) -> bool {
+        let ip = self.local_addr.ip();
+        ip == addr.ip() || (ip.is_unspecified() && ip.is_ipv4() == addr.is_ipv4())
+    }
+
     pub fn remote_addr(&self) -> Option<SocketAddr> {
         self.remote_addr
     }
@@ -120,6 +512,77 @@ impl Tcb {
         self.tuple
     }
 
+    pub fn established_at(&self) -> Option<Instant> {
+        self.established_at
+    }
+
+    /// Current retransmission timeout
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Smoothed round-trip time, if any segment has been measured yet
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn stats(&self) -> TcpStats {
+        TcpStats {
+            retransmits: self.segments_retransmitted,
+            dup_acks: self.dup_acks_received,
+            fast_path_acks: self.fast_path_acks,
+            out_of_order: self.out_of_order_segments,
+            zero_windows: self.zero_window_events,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            srtt: self.srtt,
+            rto: self.rto,
+            cwnd: self.cwnd,
+            rcv_wnd: self.rcv_wnd,
+            snd_wnd: self.snd_wnd,
+        }
+    }
+
+    /// Effective maximum segment size used when sending
+    pub fn mss(&self) -> u16 {
+        self.mss
+    }
+
+    /// Congestion window in bytes
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    /// Slow start threshold in bytes
+    pub fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    /// A printable snapshot of the state, for debugging
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot(self)
+    }
+
+    /// Sequence space sent but not yet acknowledged, SND.NXT - SND.UNA.
+    /// Includes a SYN or FIN in flight.
+    pub fn bytes_in_flight(&self) -> u32 {
+        // the sequence space wraps, so does the difference
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+
+    /// Number of retransmissions since new data was last acknowledged
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
     pub fn rx_is_empty(&self) -> bool {
         self.rx_buffer.is_empty()
     }
@@ -128,6 +591,14 @@ impl Tcb {
         self.tx_buffer.is_empty()
     }
 
+    /// Whether the peer's FIN has been consumed, no more text will arrive
+    pub fn fin_received(&self) -> bool {
+        matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait
+        )
+    }
+
     pub fn is_closing(&self) -> bool {
         matches!(self.state, State::CloseWait | State::Closed)
     }
@@ -140,12 +611,248 @@ impl Tcb {
         matches!(self.state, State::Closed)
     }
 
+    /// Whether a SYN with `seg_seq` may replace this TCB in TIME-WAIT: its
+    /// sequence number has to lie beyond the end of the old connection
+    pub fn accepts_reopening_syn(&self, seg_seq: u32) -> bool {
+        self.state == State::TimeWait && seq::gt(seg_seq, self.rcv_nxt)
+    }
+
+    /// Records why the connection failed. Like the RFC's "connection reset"
+    /// signal, it fails outstanding RECEIVEs and SENDs, so the queues are flushed.
+    pub fn set_error(&mut self, kind: io::ErrorKind) {
+        self.error = Some(kind);
+        self.tx_buffer.clear();
+        self.rx_buffer.clear();
+        self.out_of_order.clear();
+    }
+
+    pub fn error(&self) -> Option<io::ErrorKind> {
+        self.error
+    }
+
+    /// Receive urgent pointer, the sequence number following the last byte of
+    /// urgent data the peer has sent
+    pub fn urgent_pointer(&self) -> Option<u32> {
+        self.rcv_up
+    }
+
+    /// Whether urgent data the application hasn't read yet has been received
+    pub fn has_urgent_data(&self) -> bool {
+        // the first byte in rx_buffer has the sequence number RCV.NXT - len
+        let consumed = self.rcv_nxt.wrapping_sub(self.rx_buffer.len() as u32);
+        self.rcv_up.is_some_and(|rcv_up| seq::gt(rcv_up, consumed))
+    }
+
+    /// Whether the three-way handshake is still in progress
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
+    /// Whether accept() may hand out the connection, its handshake is complete
+    pub fn is_ready_to_accept(&self) -> bool {
+        matches!(self.state, State::Estab | State::CloseWait)
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Marks a passive connection as handed to the application by accept()
+    pub fn set_accepted(&mut self) {
+        self.accepted = true;
+    }
+
+    // the largest segment we can receive: the device MTU minus IP and TCP headers
+    fn local_mss(&self) -> u16 {
+        let ip_header_len = match self.tuple {
+            Some(Tuple::V6(_)) => 40,
+            _ => 20,
+        };
+        self.mtu.saturating_sub(ip_header_len + TCP_HEADER_LEN)
+    }
+
+    // apply the options of the peer's SYN
+    fn on_syn_options(&mut self, options: &TcpOptions) {
+        self.mss = options
+            .mss
+            .unwrap_or(DEFAULT_MSS)
+            .min(self.local_mss())
+            .max(1);
+        // with timestamps, every segment carries the option at the expense of data
+        match options.timestamp {
+            Some((tsval, _)) => {
+                self.timestamps = true;
+                self.ts_recent = tsval;
+                self.mss = self.mss.saturating_sub(TIMESTAMP_OPTION_LEN).max(1);
+            }
+            None => self.timestamps = false,
+        }
+        self.sack_permitted = options.sack_permitted;
+        self.cwnd = initial_cwnd(self.mss as u32);
+        tracing::debug!("effective MSS: {}", self.mss);
+
+        // window scaling is only used when both sides announce it (RFC 7323, 2.2)
+        match options.window_scale {
+            Some(shift) => {
+                self.window_scaling = true;
+                self.snd_wnd_shift = shift.min(MAX_WINDOW_SHIFT);
+                self.rcv_wnd_shift = self.local_wnd_shift();
+            }
+            None => {
+                self.window_scaling = false;
+                self.snd_wnd_shift = 0;
+                self.rcv_wnd_shift = 0;
+            }
+        }
+    }
+
+    // the smallest shift count that lets the whole receive buffer be advertised
+    fn local_wnd_shift(&self) -> u8 {
+        let mut shift = 0;
+        while shift < MAX_WINDOW_SHIFT && (self.rx_buffer.capacity() >> shift) > u16::MAX as usize {
+            shift += 1;
+        }
+        shift
+    }
+
+    // the out-of-order ranges held for reassembly, as SACK blocks
+    fn sack_blocks(&self, payload_len: usize) -> Vec<(u32, u32)> {
+        // the option shares the header with timestamps, which leaves room for 3 blocks
+        let max_blocks = if self.timestamps { 3 } else { 4 };
+        // and it must not push a segment past the MSS, each block takes 8 bytes
+        // on top of the two NOPs, kind and length
+        let room = (self.mss as usize).saturating_sub(payload_len);
+        let max_blocks = max_blocks.min(room.saturating_sub(4) / 8);
+        let mut blocks: Vec<(u32, u32)> = Vec::new();
+        for (&seg_seq, data) in &self.out_of_order {
+            let end = seg_seq.wrapping_add(data.len() as u32);
+            match blocks.last_mut() {
+                // merge adjacent and overlapping segments
+                Some((_, right)) if seq::leq(seg_seq, *right) => {
+                    if seq::gt(end, *right) {
+                        *right = end;
+                    }
+                }
+                _ => blocks.push((seg_seq, end)),
+            }
+        }
+        blocks.truncate(max_blocks);
+        blocks
+    }
+
+    // bytes the SACK option of the next segment takes, its NOPs included
+    fn sack_option_len(&self) -> usize {
+        if !self.sack_permitted {
+            return 0;
+        }
+        match self.sack_blocks(0).len() {
+            0 => 0,
+            blocks => 4 + 8 * blocks,
+        }
+    }
+
+    // record the SACK blocks of an incoming ACK
+    fn on_sack(&mut self, blocks: &[(u32, u32)]) {
+        for &(left, right) in blocks {
+            // ignore blocks that are stale or cover data we never sent
+            if !(seq::lt(left, right)
+                && seq::gt(right, self.snd_una)
+                && seq::leq(right, self.snd_nxt))
+            {
+                continue;
+            }
+            if !self.is_sacked(left, right.wrapping_sub(left) as usize) {
+                self.sacked.push((left, right));
+            }
+        }
+    }
+
+    // whether the peer reported [seq, seq + len) as received
+    fn is_sacked(&self, seg_seq: u32, len: usize) -> bool {
+        let end = seg_seq.wrapping_add(len as u32);
+        len > 0
+            && self
+                .sacked
+                .iter()
+                .any(|&(left, right)| seq::leq(left, seg_seq) && seq::geq(right, end))
+    }
+
+    // the current value of our timestamp clock, in milliseconds
+    fn ts_now(&self) -> u32 {
+        self.ts_offset
+            .wrapping_add(self.ts_base.elapsed().as_millis() as u32)
+    }
+
+    // the window of a segment, scaled unless it is a SYN
+    fn seg_wnd(&self, hdr: &etherparse::TcpHeaderSlice) -> u32 {
+        let wnd = hdr.window_size() as u32;
+        if hdr.syn() {
+            wnd
+        } else {
+            wnd << self.snd_wnd_shift
+        }
+    }
+
+    // the window field of an outgoing segment
+    fn advertised_wnd(&self, syn: bool) -> u16 {
+        let shift = if syn { 0 } else { self.rcv_wnd_shift };
+        (self.rcv_wnd >> shift).min(u16::MAX as u32) as u16
+    }
+
+    // Receiver SWS avoidance (RFC 1122, 4.2.3.3): the right edge of the window
+    // only advances once it can move by at least min(MSS, buffer / 2), so a
+    // slowly reading application doesn't invite tiny segments.
+    fn update_rcv_wnd(&mut self) {
+        let available = self.rx_window() as u32;
+        let threshold = (self.local_mss() as usize).min(self.rx_buffer.capacity() / 2) as u32;
+        if available >= self.rcv_wnd.saturating_add(threshold) {
+            self.rcv_wnd = available;
+        } else {
+            self.rcv_wnd = self.rcv_wnd.min(available);
+        }
+    }
+
     fn rx_window(&self) -> usize {
-        self.rx_buffer.capacity() - self.rx_buffer.len()
+        self.rx_buffer.free()
+    }
+
+    // every state change goes through here, so transitions show up in the logs
+    fn set_state(&mut self, state: State) {
+        tracing::debug!(from = ?self.state, to = ?state, "state transition");
+        self.state = state;
+    }
+
+    // enter TIME-WAIT, start the time-wait timer and turn off the other timers
+    fn enter_time_wait(&mut self) {
+        self.set_state(State::TimeWait);
+        self.timers.cancel_all_rto();
+        self.timers.start_time_wait(2 * self.msl);
+    }
+
+    /// Whether `seg_ack` acknowledges our FIN
+    fn acks_fin(&self, seg_ack: u32) -> bool {
+        self.fin_seq
+            .is_some_and(|fin_seq| seg_ack == fin_seq.wrapping_add(1))
+    }
+
+    /// Data bytes sent but not yet acknowledged.
+    ///
+    /// Our FIN occupies a sequence number after the last data byte but no
+    /// space in `tx_buffer` or in the peer's window, so it is left out.
+    fn data_in_flight(&self) -> usize {
+        let in_flight = self.bytes_in_flight() as usize;
+        if self.fin_seq.is_some() && in_flight > 0 {
+            in_flight - 1
+        } else {
+            in_flight
+        }
     }
 
-    fn tx_window(&self) -> usize {
-        self.tx_buffer.capacity() - self.tx_buffer.len()
+    // usable window: SND.UNA + min(SND.WND, CWND) - SND.NXT, never negative
+    fn usable_wnd(&self) -> usize {
+        (self.snd_wnd as usize)
+            .min(self.cwnd as usize)
+            .saturating_sub(self.data_in_flight())
     }
 
     fn segment_length(hdr: &etherparse::TcpHeaderSlice, len: usize) -> u32 {
@@ -172,12 +879,9 @@ impl Tcb {
         //              or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
         let seg_seq = hdr.sequence_number();
         let seg_len = Self::segment_length(hdr, len);
-        let seg_end = if seg_len > 0 {
-            seg_seq.wrapping_add(seg_len - 1)
-        } else {
-            seg_seq
-        };
-        let rcv_win = self.rcv_nxt + self.rcv_wnd as u32;
+        // only meaningful for segments that occupy sequence space
+        let seg_end = seg_seq.wrapping_add(seg_len.saturating_sub(1));
+        let rcv_win = self.rcv_nxt.wrapping_add(self.rcv_wnd);
 
         match (seg_len, self.rcv_wnd) {
             (0, 0) => {
@@ -186,14 +890,14 @@ impl Tcb {
                 }
             }
             (0, window) if window > 0 => {
-                if self.rcv_nxt <= seg_seq && seg_seq < rcv_win {
+                if seq::between(self.rcv_nxt, seg_seq, rcv_win) {
                     return true;
                 }
             }
             (length, 0) if length > 0 => return false,
             (length, window) if length > 0 && window > 0 => {
-                if self.rcv_nxt <= seg_seq && seg_seq < rcv_win
-                    || self.rcv_nxt <= seg_end && seg_end < rcv_win
+                if seq::between(self.rcv_nxt, seg_seq, rcv_win)
+                    || seq::between(self.rcv_nxt, seg_end, rcv_win)
                 {
                     return true;
                 }
@@ -205,35 +909,158 @@ impl Tcb {
         false
     }
 
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        self.keepalive_probes = 0;
+    }
+
+    /// Resets the connection once it has been idle for `timeout`, `None` never does
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets the window advertised on the SYN-ACK of connections accepted by this TCB,
+    /// capped at the size of the receive buffer
+    pub fn set_initial_rcv_wnd(&mut self, wnd: u16) {
+        self.initial_rcv_wnd = Some(wnd);
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    /// Sets the DSCP bits of the TOS / traffic class byte, the two ECN bits
+    /// are left to the stack, which doesn't use ECN
+    pub fn set_tos(&mut self, tos: u8) {
+        self.tos = tos & !ECN_MASK;
+        self.options.tos = self.tos;
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+        // a listener hands it down to the connections it accepts
+        self.options.ttl = ttl;
+    }
+
+    pub fn backlog(&self) -> usize {
+        self.backlog
+    }
+
+    /// Sets how long connections accepted by this listener may stay half-open
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
     pub fn listen(&mut self) {
-        self.state = State::Listen;
+        self.set_state(State::Listen);
+    }
+
+    /// Drops the connection without the FIN handshake, the peer is sent a RST
+    /// from `on_tick`
+    pub fn abort(&mut self) {
+        // ABORT call: states that have synchronized with the peer reset it,
+        // CLOSING, LAST-ACK and TIME-WAIT only delete the TCB
+        self.rst_pending = matches!(
+            self.state,
+            State::SynRcvd | State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait
+        );
+        self.set_state(State::Closed);
+        self.tx_buffer.clear();
+        self.rx_buffer.clear();
+        self.out_of_order.clear();
+        self.timers.cancel_all_rto();
     }
 
     pub fn init_closing(&mut self) {
-        if self.state != State::CloseWait {
-            return;
+        // the FIN is sent from on_tick once the queued data has been sent
+        match self.state {
+            State::Estab => self.set_state(State::FinWait1),
+            State::CloseWait => self.set_state(State::LastAck),
+            _ => {}
         }
-        self.state = State::LastAck;
+    }
+
+    // stop delivering text to the application, it is still acknowledged
+    pub fn shutdown_read(&mut self) {
+        self.rx_shutdown = true;
+        self.rx_buffer.clear();
+        self.out_of_order.clear();
+        self.out_of_order_fin = None;
+    }
+
+    pub fn is_read_shutdown(&self) -> bool {
+        self.rx_shutdown
+    }
+
+    // copy from the front of the receive buffer without consuming anything
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = std::cmp::min(buf.len(), self.rx_buffer.len());
+        // the ring buffer may wrap, copy from both of its halves
+        let (head, tail) = self.rx_buffer.as_slices();
+        let from_head = to_read.min(head.len());
+        buf[..from_head].copy_from_slice(&head[..from_head]);
+        buf[from_head..to_read].copy_from_slice(&tail[..to_read - from_head]);
+        Ok(to_read)
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let available = self.rx_buffer.len();
-        let to_read = std::cmp::min(buf.len(), available);
-        let drained = self.rx_buffer.drain(..to_read).collect::<Vec<u8>>();
-        buf[..to_read].copy_from_slice(&drained);
+        let to_read = self.peek(buf)?;
+        self.rx_buffer.consume(to_read);
+        if to_read > 0 {
+            self.last_activity = Instant::now();
+        }
+
+        // the peer may be stalled on a small window, tell it about the freed space
+        let advertised = self.rcv_wnd;
+        self.update_rcv_wnd();
+        if self.rcv_wnd > advertised && (advertised as usize) < self.rx_buffer.capacity() / 2 {
+            self.window_update_pending = true;
+        }
         Ok(to_read)
     }
 
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let to_write = std::cmp::min(self.tx_window(), buf.len());
-        self.tx_buffer.extend(&buf[..to_write]);
+        // data can't be queued once our side of the connection has been closed
+        if !matches!(
+            self.state,
+            State::SynSent | State::SynRcvd | State::Estab | State::CloseWait
+        ) {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        let to_write = self.tx_buffer.write(buf);
+        self.bytes_sent += to_write as u64;
+        if to_write > 0 {
+            self.last_activity = Instant::now();
+        }
         Ok(to_write)
     }
 
+    // actively open a connection, on_tick sends the SYN
+    pub fn connect(&mut self, tuple: Tuple) {
+        self.remote_addr = Some(tuple.remote_ip());
+        self.tuple = Some(tuple);
+        self.connection_type = ConnectionType::Active;
+        // offer window scaling and timestamps, they are turned off again unless
+        // the SYN-ACK carries them too
+        self.window_scaling = true;
+        self.timestamps = true;
+        self.sack_permitted = true;
+        self.rcv_wnd_shift = self.local_wnd_shift();
+        self.snd_una = self.iss;
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.rcv_wnd = self.rx_window() as u32;
+        self.set_state(State::SynSent);
+        self.syn_pending = true;
+    }
+
     // half-establish a connection
     pub fn try_establish(
         &mut self,
-        dev: &mut device::TunDevice,
+        dev: &mut impl PacketDevice,
         hdr: &etherparse::TcpHeaderSlice,
         tuple: Tuple,
     ) -> io::Result<Option<Tcb>> {
@@ -248,7 +1075,7 @@ impl Tcb {
         }
 
         /* security and precedence checks are skipped */
-        let mut tcb = Tcb::new(tuple.local_ip());
+        let mut tcb = Tcb::with_options(tuple.local_ip(), self.options);
         tcb.remote_addr = Some(tuple.remote_ip());
         tcb.tuple = Some(tuple);
 
@@ -257,67 +1084,93 @@ impl Tcb {
         }
 
         if hdr.syn() {
+            tcb.mtu = dev.mtu();
+            tcb.on_syn_options(&TcpOptions::parse(hdr.options()).unwrap_or_default());
             tcb.connection_type = ConnectionType::Passive;
             tcb.irs = hdr.sequence_number();
             tcb.rcv_nxt = hdr.sequence_number().wrapping_add(1);
-            tcb.rcv_wnd = tcb.rx_window() as u16;
+            // the window of a SYN is never scaled
+            tcb.snd_wnd = hdr.window_size() as u32;
+            // a configured initial window may shrink the window, never exceed the buffer
+            tcb.rcv_wnd = self
+                .initial_rcv_wnd
+                .map_or(tcb.rx_window() as u32, u32::from)
+                .min(tcb.rx_window() as u32);
             tcb.snd_una = tcb.iss;
             tcb.snd_nxt = tcb.iss.wrapping_add(1);
-            tcb.state = State::SynRcvd;
+            tcb.set_state(State::SynRcvd);
+            tcb.handshake_timeout = self.handshake_timeout;
+            tcb.syn_rcvd_at = Some(Instant::now());
 
             let flags = TcpFlags {
                 syn: true,
                 ..Default::default()
             };
             tcb.send(dev, tcb.iss, Some(tcb.rcv_nxt), &flags, &[])?;
-            self.timers.start_rto(tcb.iss, flags, self.rto, 0);
+            tcb.timers.start_rto(tcb.iss, flags, tcb.rto, 0);
             return Ok(Some(tcb));
         }
 
         Ok(None)
     }
 
-    pub fn on_tick(&mut self, dev: &mut device::TunDevice) -> io::Result<()> {
-        if !matches!(self.state, State::Estab | State::CloseWait | State::LastAck) {
+    pub fn on_tick(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        tracing::trace!("tick: {}", self.snapshot());
+        if self.rst_pending {
+            // <SEQ=SND.NXT><CTL=RST>
+            self.rst_pending = false;
+            tracing::info!("connection aborted, resetting {:?}", self.remote_addr);
+            return self.send_rst(dev, self.snd_nxt);
+        }
+        if matches!(self.state, State::SynSent | State::SynRcvd) {
+            return self.on_handshake_tick(dev);
+        }
+        if self.state == State::TimeWait {
+            if self.timers.time_wait_expired() {
+                tracing::debug!("TIME-WAIT expired, closing");
+                self.set_state(State::Closed);
+            }
             return Ok(());
         }
+        if !matches!(
+            self.state,
+            State::Estab
+                | State::CloseWait
+                | State::LastAck
+                | State::FinWait1
+                | State::FinWait2
+                | State::Closing
+        ) {
+            return Ok(());
+        }
+        if self.timers.delayed_ack_expired() {
+            tracing::trace!("delayed ACK timer expired");
+            self.send_ack(dev)?;
+        }
+        if self.window_update_pending {
+            self.window_update_pending = false;
+            tracing::debug!("sending window update: WND={}", self.rcv_wnd);
+            self.send_ack(dev)?;
+        }
+        self.on_idle(dev)?;
+        self.on_keepalive(dev)?;
         if let Some((seq, timer)) = self.timers.find_expired() {
-            let start = seq.wrapping_sub(self.snd_una) as usize;
-            let end = start + timer.payload_len();
-
-            assert!(start <= end);
-
-            tracing::debug!(
-                "expired: local start_idx: {}, end_idx: {}, tx_len: {}",
-                start,
-                end,
-                self.tx_buffer.len()
-            );
-
-            let payload: Vec<u8> = self.tx_buffer.range(start..end).copied().collect();
-
-            tracing::debug!(
-                "retransmitting payload: {:?}",
-                String::from_utf8_lossy(payload.as_slice())
-            );
-
-            self.send(
-                dev,
-                seq,
-                Some(self.rcv_nxt),
-                timer.flags(),
-                payload.as_slice(),
-            )?;
-
-            // TODO: measure RTO properly
-            self.rto *= 2;
-
-            self.timers
-                .start_rto(seq, timer.flags().to_owned(), self.rto, timer.payload_len());
-        } else if !self.tx_is_empty() {
-            let available_wnd =
-                self.snd_wnd
-                    .wrapping_sub((self.snd_nxt - self.snd_una) as u16) as usize;
+            if self.is_sacked(seq, timer.payload_len()) {
+                // the peer already holds the segment, it only waits for an earlier hole
+                tracing::debug!("not retransmitting selectively acknowledged SEQ={}", seq);
+                self.timers
+                    .start_rto(seq, timer.flags().to_owned(), self.rto, timer.payload_len());
+            } else {
+                self.retransmit_expired(dev, seq, timer)?;
+            }
+        } else if self.snd_wnd == 0 && !self.tx_is_empty() && self.fin_seq.is_none() {
+            // the peer closed its window, probe it so we learn when it reopens
+            self.on_persist(dev)?;
+        } else if self.fin_seq.is_none() && self.data_in_flight() < self.tx_buffer.len() {
+            // there is unsent data, and no data may follow our FIN
+            let in_flight = self.data_in_flight();
+            let unsent = self.tx_buffer.len() - in_flight;
+            let available_wnd = self.usable_wnd();
 
             // no data can be sent, skip
             if available_wnd == 0 {
@@ -325,17 +1178,23 @@ impl Tcb {
             }
 
             let (head, tail) = self.tx_buffer.as_slices();
-            let to_write = std::cmp::min(available_wnd.min(TUN_MTU as usize), self.tx_buffer.len());
+            // leave room for the SACK blocks, retransmissions of a full segment go without
+            let seg_max = (self.mss as usize)
+                .saturating_sub(self.sack_option_len())
+                .max(1);
+            let to_write = available_wnd.min(seg_max).min(unsent);
             let mut remaining = to_write;
-            let mut window_left = available_wnd;
-            let mut cur_slice = head;
-            let mut cur_pos = 0; // offset within cur_slice
+            // skip the bytes already in flight, they are only resent on RTO
+            let (mut cur_slice, mut cur_pos) = if in_flight < head.len() {
+                (head, in_flight)
+            } else {
+                (tail, in_flight - head.len())
+            };
             let mut seq = self.snd_nxt;
 
             /* send segments in batches */
-            while remaining > 0 && !self.tx_is_empty() && window_left > 0 {
-                let seg_size: usize =
-                    std::cmp::min(remaining, (cur_slice.len() - cur_pos).min(window_left));
+            while remaining > 0 {
+                let seg_size: usize = std::cmp::min(remaining, cur_slice.len() - cur_pos);
 
                 let flags = TcpFlags {
                     psh: true,
@@ -365,7 +1224,6 @@ impl Tcb {
                 }
 
                 remaining -= seg_size;
-                window_left -= seg_size;
                 cur_pos += seg_size;
 
                 if cur_pos >= cur_slice.len() {
@@ -375,9 +1233,17 @@ impl Tcb {
             }
             // when a sender creates a segment and transmits it the sender advances SND.NXT
             self.snd_nxt = seq;
+            // the data segments carried the ACK as well
+            self.ack_sent();
         }
 
-        if self.state == State::LastAck {
+        // the FIN goes out only once every buffered byte has been sent
+        if matches!(
+            self.state,
+            State::LastAck | State::FinWait1 | State::Closing
+        ) && self.fin_seq.is_none()
+            && self.data_in_flight() == self.tx_buffer.len()
+        {
             // <SEQ=seq><ACK=rcv_nxt><CTL=FIN,ACK>
             let seq = self.snd_nxt;
             let flags = TcpFlags {
@@ -387,246 +1253,956 @@ impl Tcb {
             self.send(dev, seq, Some(self.rcv_nxt), &flags, &[])?;
             // syn & fin take one seq number, so they can be retransmitted
             self.timers.start_rto(seq, flags, self.rto, 0);
+            self.fin_seq = Some(seq);
             self.snd_nxt = self.snd_nxt.wrapping_add(1);
         }
 
         Ok(())
     }
 
-    pub(crate) fn on_segment(
-        &mut self,
-        dev: &mut device::TunDevice,
-        tcph: &etherparse::TcpHeaderSlice,
-        payload: &[u8],
-        read_cvar: &Condvar,
-    ) -> io::Result<()> {
-        // try to establish a connection
+    /// The instant `on_tick` next has work to do, or `None` when the TCB only
+    /// waits for segments or for the application
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let now = Instant::now();
         match self.state {
-            State::SynSent => {
-                return self.process_syn_sent(dev, tcph);
-            }
-            State::Closed => {
-                return self.process_close(dev, tcph, payload);
+            State::SynSent if self.syn_pending => return Some(now),
+            State::SynSent | State::SynRcvd => {
+                let handshake = self
+                    .syn_rcvd_at
+                    .filter(|_| self.state == State::SynRcvd)
+                    .map(|at| at + self.handshake_timeout);
+                return [self.timers.next_rto_expiry(), handshake]
+                    .into_iter()
+                    .flatten()
+                    .min();
             }
-            _ => {}
+            State::TimeWait => return self.timers.time_wait_expiry(),
+            // a closed TCB is reaped on its next tick
+            State::Closed => return Some(now),
+            State::Estab
+            | State::CloseWait
+            | State::LastAck
+            | State::FinWait1
+            | State::FinWait2
+            | State::Closing => {}
+            _ => return None,
         }
-        // check sequence number
-        if !matches!(self.state, State::Listen | State::SynSent | State::Closed)
-            && !self.is_acceptable(tcph, payload.len())
+        let unsent = self.fin_seq.is_none() && self.data_in_flight() < self.tx_buffer.len();
+        let zero_wnd = self.snd_wnd == 0 && !self.tx_is_empty() && self.fin_seq.is_none();
+        let fin_queued = matches!(
+            self.state,
+            State::LastAck | State::FinWait1 | State::Closing
+        ) && self.fin_seq.is_none()
+            && self.data_in_flight() == self.tx_buffer.len();
+        if self.window_update_pending
+            || (zero_wnd && !self.timers.persist_running())
+            || (unsent && !zero_wnd && self.usable_wnd() > 0)
+            || fin_queued
         {
-            self.send_ack(dev)?;
+            return Some(now);
         }
-        // check the RST bit
-        if tcph.rst() {
-            match self.state {
-                State::SynRcvd => {
-                    if self.connection_type == ConnectionType::Passive {
-                        self.state = State::Listen;
-                        return Ok(());
-                    } else {
-                        self.tx_buffer.clear();
-                        return Err(io::Error::from(io::ErrorKind::ConnectionReset));
-                    }
-                }
-                State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
-                    // Any outstanding RECEIVEs and SEND should receive "reset" responses.
-                    // All segment queues should be flushed. Users should also receive an unsolicited general
-                    // "connection reset" signal. Enter the CLOSED state, delete the
-                    //TCB, and return.
-                    self.state = State::Closed;
-                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
-                }
-                State::Closing | State::LastAck | State::TimeWait => {
-                    self.state = State::Closed;
-                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
-                }
-                _ => {}
-            }
+        // probes are only sent on an idle connection, see on_keepalive
+        let keepalive = self
+            .keepalive
+            .filter(|_| {
+                matches!(self.state, State::Estab | State::CloseWait)
+                    && self.tx_is_empty()
+                    && self.snd_una == self.snd_nxt
+            })
+            .map(|keepalive| {
+                self.last_recv + keepalive.idle + keepalive.interval * self.keepalive_probes
+            });
+        let idle = self
+            .idle_timeout
+            .map(|timeout| self.last_activity + timeout);
+        [
+            self.timers.next_rto_expiry(),
+            self.timers.delayed_ack_expiry(),
+            self.timers.persist_expiry().filter(|_| zero_wnd),
+            keepalive,
+            idle,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    // resend a segment whose retransmission timer expired and back off
+    fn retransmit_expired(
+        &mut self,
+        dev: &mut impl PacketDevice,
+        seq: u32,
+        timer: RTOEntry,
+    ) -> io::Result<()> {
+        if self.retransmits >= self.max_retransmits {
+            tracing::info!(
+                "no ACK after {} retransmissions, giving up",
+                self.retransmits
+            );
+            self.set_state(State::Closed);
+            self.timers.cancel_all_rto();
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
         }
-        /* security and precedence checks are skipped */
+        let start = seq.wrapping_sub(self.snd_una) as usize;
+        let end = start + timer.payload_len();
 
-        // check the SYN bit
-        if tcph.syn() && !matches!(self.state, State::Closed | State::SynSent) {
-            // If the SYN is in the window it is an error, send a reset, any
-            // outstanding RECEIVEs and SEND should receive "reset" responses,
-            // all segment queues should be flushed, the user should also
-            // receive an unsolicited general "connection reset" signal, enter
-            // the CLOSED state, delete the TCB, and return.
+        assert!(start <= end);
+
+        tracing::debug!(
+            "retransmitting seq {} with len {}, tx_len: {}",
+            seq,
+            end - start,
+            self.tx_buffer.len()
+        );
+
+        let payload: Vec<u8> = self.tx_buffer.range(start..end).copied().collect();
+
+        self.send(
+            dev,
+            seq,
+            Some(self.rcv_nxt),
+            timer.flags(),
+            payload.as_slice(),
+        )?;
+
+        // a timeout is a loss the ACK clock couldn't recover from, start over in slow start
+        if self.retransmits == 0 {
+            self.ssthresh = self.loss_ssthresh();
+        }
+        self.cwnd = self.mss as u32;
+        self.recover = None;
+        self.dup_acks = 0;
+        self.retransmits += 1;
+        self.segments_retransmitted += 1;
+        self.rto = self.clamp_rto(self.rto * 2);
+
+        self.timers
+            .restart_rto(seq, timer.flags().to_owned(), self.rto, timer.payload_len());
+        Ok(())
+    }
+
+    fn on_handshake_tick(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        if self.syn_pending {
+            self.syn_pending = false;
+            // the MSS we announce depends on the device
+            self.mtu = dev.mtu();
+            // <SEQ=ISS><CTL=SYN>
+            let flags = TcpFlags {
+                syn: true,
+                ..Default::default()
+            };
+            self.send(dev, self.iss, None, &flags, &[])?;
+            self.timers.start_rto(self.iss, flags, self.rto, 0);
+            return Ok(());
+        }
+        // reap connections that never complete the handshake, e.g. during a SYN flood
+        if self.state == State::SynRcvd
+            && self
+                .syn_rcvd_at
+                .is_some_and(|at| at.elapsed() >= self.handshake_timeout)
+        {
+            tracing::info!("handshake with {:?} timed out, resetting", self.remote_addr);
+            self.send_rst(dev, self.snd_nxt)?;
+            self.set_state(State::Closed);
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        let Some((seq, timer)) = self.timers.find_expired() else {
+            return Ok(());
+        };
+        if self.syn_retries >= self.max_syn_retries {
+            tracing::info!(
+                "SYN unanswered after {} retransmissions, giving up",
+                self.syn_retries
+            );
+            self.set_state(State::Closed);
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        tracing::debug!("retransmitting SYN, attempt {}", self.syn_retries + 1);
+        // a SYN-ACK additionally acknowledges the peer's SYN
+        let ack = (self.state == State::SynRcvd).then_some(self.rcv_nxt);
+        self.send(dev, seq, ack, timer.flags(), &[])?;
+        self.syn_retries += 1;
+        self.segments_retransmitted += 1;
+        self.rto = self.clamp_rto(self.rto * 2);
+        self.timers
+            .restart_rto(seq, timer.flags().to_owned(), self.rto, 0);
+        Ok(())
+    }
+
+    fn on_persist(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        if !self.timers.persist_running() {
+            tracing::debug!("peer advertised a zero window, starting the persist timer");
+            self.zero_window_events += 1;
+            self.timers.start_persist(self.persist_interval);
+            return Ok(());
+        }
+        if !self.timers.persist_expired() {
+            return Ok(());
+        }
+        // <SEQ=SND.UNA><ACK=RCV.NXT> carrying the first unacknowledged byte
+        let probe = [self.tx_buffer[0]];
+        self.send(
+            dev,
+            self.snd_una,
+            Some(self.rcv_nxt),
+            &TcpFlags::default(),
+            &probe,
+        )?;
+        if self.snd_nxt == self.snd_una {
+            self.snd_nxt = self.snd_una.wrapping_add(1);
+        }
+        self.persist_interval = (self.persist_interval * 2).min(PERSIST_MAX);
+        self.timers.start_persist(self.persist_interval);
+        tracing::debug!(
+            "sent zero-window probe, next in {:?}",
+            self.persist_interval
+        );
+        Ok(())
+    }
+
+    // reset a connection abandoned by both the peer and the application
+    fn on_idle(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        let Some(timeout) = self.idle_timeout else {
+            return Ok(());
+        };
+        if self.last_activity.elapsed() < timeout {
+            return Ok(());
+        }
+        tracing::info!("connection idle for {:?}, resetting", timeout);
+        // <SEQ=SND.NXT><CTL=RST>
+        self.send_rst(dev, self.snd_nxt)?;
+        self.set_state(State::Closed);
+        self.timers.cancel_all_rto();
+        Err(io::Error::from(io::ErrorKind::TimedOut))
+    }
+
+    fn on_keepalive(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        let Some(keepalive) = self.keepalive else {
+            return Ok(());
+        };
+        // only probe an idle connection, outstanding data has its own RTO
+        if !matches!(self.state, State::Estab | State::CloseWait)
+            || !self.tx_is_empty()
+            || self.snd_una != self.snd_nxt
+        {
+            return Ok(());
+        }
+        let deadline = keepalive.idle + keepalive.interval * self.keepalive_probes;
+        if self.last_recv.elapsed() < deadline {
+            return Ok(());
+        }
+        if self.keepalive_probes >= keepalive.probes {
+            tracing::info!(
+                "peer did not answer {} keepalive probes, closing",
+                self.keepalive_probes
+            );
+            self.set_state(State::Closed);
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        // <SEQ=SND.NXT-1><ACK=RCV.NXT><CTL=ACK>
+        self.send(
+            dev,
+            self.snd_nxt.wrapping_sub(1),
+            Some(self.rcv_nxt),
+            &TcpFlags::default(),
+            &[],
+        )?;
+        self.keepalive_probes += 1;
+        Ok(())
+    }
+
+    // any acceptable segment proves the peer is alive, on the fast path as well
+    fn on_peer_alive(&mut self) {
+        self.last_recv = Instant::now();
+        self.last_activity = self.last_recv;
+        self.keepalive_probes = 0;
+    }
+
+    pub(crate) fn on_segment(
+        &mut self,
+        dev: &mut impl PacketDevice,
+        tcph: &etherparse::TcpHeaderSlice,
+        payload: &[u8],
+        read_cvar: &Condvar,
+        write_cvar: &Condvar,
+    ) -> io::Result<()> {
+        // fast path for the common case: a pure, in-order ACK of new data.
+        // It takes no options or, once negotiated, aligned timestamps only,
+        // `Some(None)` stands for a segment without options.
+        let seg_ack = tcph.acknowledgment_number();
+        let predicted_ts = match tcph.options() {
+            [] => Some(None),
+            options if self.timestamps => TcpOptions::aligned_timestamp(options).map(Some),
+            _ => None,
+        };
+        if let Some(ts) = predicted_ts
+            && self.state == State::Estab
+            && tcph.ack()
+            && !(tcph.syn() || tcph.fin() || tcph.rst() || tcph.urg())
+            && payload.is_empty()
+            && tcph.sequence_number() == self.rcv_nxt
+            && seq::lt(self.snd_una, seg_ack)
+            && seq::leq(seg_ack, self.snd_nxt)
+            // PAWS, an old timestamp goes down the slow path to be dropped
+            && ts.is_none_or(|(tsval, _)| seq::geq(tsval, self.ts_recent))
+        {
+            // the segment starts at RCV.NXT, so its timestamp is the one to echo
+            if let Some((tsval, _)) = ts {
+                self.ts_recent = tsval;
+            }
+            self.fast_path_acks += 1;
+            self.on_peer_alive();
+            self.on_new_ack(
+                dev,
+                tcph.sequence_number(),
+                seg_ack,
+                self.seg_wnd(tcph),
+                ts.map(|(_, ts_ecr)| ts_ecr),
+            )?;
+            write_cvar.notify_all(); // acknowledged data freed space in tx_buffer
+            return Ok(());
+        }
+
+        // unknown options are skipped, only a malformed options area drops the segment
+        let options = match TcpOptions::parse(tcph.options()) {
+            Ok(options) => options,
+            Err(e) => {
+                tracing::warn!("dropping segment: {}", e);
+                return Ok(());
+            }
+        };
+        tracing::trace!("segment options: {:?}", options);
+
+        // try to establish a connection
+        match self.state {
+            State::SynSent => {
+                let result = self.process_syn_sent(dev, tcph);
+                // connect() waits for the handshake on the write cvar
+                write_cvar.notify_all();
+                return result;
+            }
+            State::Closed => {
+                return self.process_close(dev, tcph, payload);
+            }
+            _ => {}
+        }
+        // PAWS: a timestamp older than TS.Recent marks an old duplicate (RFC 7323, 5.3)
+        let ts = options.timestamp.filter(|_| self.timestamps);
+        if let Some((tsval, _)) = ts
+            && !tcph.rst()
+            && seq::lt(tsval, self.ts_recent)
+        {
+            tracing::debug!("PAWS: dropping segment with TSval {}", tsval);
+            return self.send_ack(dev);
+        }
+        // check sequence number
+        if !matches!(self.state, State::Listen | State::SynSent | State::Closed)
+            && !self.is_acceptable(tcph, payload.len())
+        {
+            // old duplicates and segments beyond the window are acknowledged and dropped
+            if !tcph.rst() {
+                self.send_ack(dev)?;
+            }
+            if self.state == State::TimeWait && tcph.fin() {
+                // a retransmitted FIN means our last ACK was lost, restart the 2 MSL timeout
+                self.timers.start_time_wait(2 * self.msl);
+            }
+            return Ok(());
+        }
+        self.on_peer_alive();
+        // remember the timestamp to echo, unless the segment starts beyond our last ACK
+        if let Some((tsval, _)) = ts
+            && seq::geq(tsval, self.ts_recent)
+            && seq::leq(tcph.sequence_number(), self.rcv_nxt)
+        {
+            self.ts_recent = tsval;
+        }
+
+        // check the RST bit
+        if tcph.rst() {
+            // RFC 5961, 3.2: only a RST exactly at RCV.NXT resets the connection.
+            // Any other in-window RST may be a blind reset, challenge the peer
+            // instead: a genuine one answers with a RST carrying the right SEQ.
+            if tcph.sequence_number() != self.rcv_nxt {
+                tracing::debug!(
+                    "RST with SEQ={} instead of {}, sending a challenge ACK",
+                    tcph.sequence_number(),
+                    self.rcv_nxt
+                );
+                return self.send_challenge_ack(dev);
+            }
+            match self.state {
+                State::SynRcvd => {
+                    // the listener itself never left LISTEN, a connection nobody
+                    // accepted yet just falls back to it and is dropped
+                    if self.connection_type == ConnectionType::Passive && !self.accepted {
+                        self.set_state(State::Listen);
+                        return Ok(());
+                    }
+                    // an accepted or actively opened one has a user to tell
+                    self.tx_buffer.clear();
+                    self.set_state(State::Closed);
+                    read_cvar.notify_all();
+                    write_cvar.notify_all();
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                }
+                State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+                    // Any outstanding RECEIVEs and SEND should receive "reset" responses.
+                    // All segment queues should be flushed. Users should also receive an unsolicited general
+                    // "connection reset" signal. Enter the CLOSED state, delete the
+                    //TCB, and return.
+                    self.set_state(State::Closed);
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                }
+                State::Closing | State::LastAck | State::TimeWait => {
+                    self.set_state(State::Closed);
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                }
+                _ => {}
+            }
+        }
+        /* security and precedence checks are skipped */
+
+        // check the SYN bit
+        if tcph.syn() && !matches!(self.state, State::Closed | State::SynSent) {
+            // RFC 5961, 4.2: rather than resetting on an in-window SYN, which a
+            // blind attacker could forge, send a challenge ACK and drop the segment.
+            // A peer that really restarted answers with a RST at RCV.NXT.
             //
-            // If the SYN is not in the window self step would not be reached
-            // and an ack would have been sent in the first step (sequence
-            // number check).
+            // A SYN outside the window never gets here, it was acknowledged
+            // in the sequence number check.
+            tracing::debug!(
+                "in-window SYN with SEQ={}, sending a challenge ACK",
+                tcph.sequence_number()
+            );
+            return self.send_challenge_ack(dev);
         }
         if tcph.ack() {
             let seg_ack = tcph.acknowledgment_number();
             let seg_seq = tcph.sequence_number();
-            let seg_wnd = tcph.window_size();
+            let seg_wnd = self.seg_wnd(tcph);
+            let ts_ecr = ts.map(|(_, ts_ecr)| ts_ecr);
+            if self.sack_permitted {
+                self.on_sack(&options.sack);
+            }
             match self.state {
-                State::SynRcvd => match seg_ack > self.snd_una && seg_ack <= self.snd_nxt {
-                    true => {
-                        if tcph.rst() {
-                            self.state = State::Closed;
-                            return Err(io::Error::from(io::ErrorKind::ConnectionReset));
-                        }
-                        self.state = State::Estab;
-                    }
-                    false => {
-                        self.send_rst(dev, tcph.sequence_number())?;
-                    }
-                },
-                State::Estab | State::CloseWait => {
-                    if self.snd_una < seg_ack && seg_ack <= self.snd_nxt {
-                        let ack_idx = (seg_ack - self.snd_una) as usize;
-                        // remove everything up to seg_ack
-                        self.tx_buffer.drain(..ack_idx.min(self.tx_buffer.len()));
-                        self.snd_una = seg_ack;
-
-                        // cancel the retransmit timer/s associated with the snd_una
-                        self.timers.find_rto_by_ack(seg_ack, |seq, rto_entry| {
-                            tracing::debug!(
-                                "RTO for seq {} with payloa_len {} acked",
-                                seq,
-                                rto_entry.payload_len()
-                            );
-                            self.rto = Duration::from_millis(200);
-                        });
-
-                        // updating the window from send sequence space
-                        if self.snd_wl1 < seg_seq
-                            || (self.snd_wl1 == seg_seq && self.snd_wl2 <= seg_ack)
-                        {
+                State::SynRcvd => {
+                    match seq::gt(seg_ack, self.snd_una) && seq::leq(seg_ack, self.snd_nxt) {
+                        true => {
+                            if tcph.rst() {
+                                self.set_state(State::Closed);
+                                return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                            }
+                            // our SYN-ACK has been acknowledged, stop retransmitting it
+                            // Karn: a retransmitted SYN can't be timed
+                            if let Some(entry) = self.timers.cancel_rto(self.iss)
+                                && !entry.retransmitted()
+                            {
+                                self.on_rtt_sample(entry.sent_at().elapsed());
+                            }
+                            self.set_state(State::Estab);
+                            self.established_at = Some(Instant::now());
+                            // the SYN is acknowledged, or data queued by then counts it as in flight
+                            self.snd_una = seg_ack;
                             self.snd_wnd = seg_wnd;
                             self.snd_wl1 = seg_seq;
                             self.snd_wl2 = seg_ack;
+                            // a simultaneous open's connect() is still waiting
+                            write_cvar.notify_all();
+                        }
+                        false => {
+                            self.send_rst(dev, tcph.sequence_number())?;
                         }
                     }
-                    if seg_ack > self.snd_una {
-                        // If the ACK is duplicate it can be ignored
-                        return Ok(());
-                    }
-                    // If the ACK acks something not yet sent
-                    if seg_ack > self.snd_nxt {
+                }
+                State::Estab
+                | State::CloseWait
+                | State::FinWait1
+                | State::FinWait2
+                | State::Closing
+                | State::LastAck => {
+                    if seq::lt(self.snd_una, seg_ack) && seq::leq(seg_ack, self.snd_nxt) {
+                        self.on_new_ack(dev, seg_seq, seg_ack, seg_wnd, ts_ecr)?;
+                        write_cvar.notify_all(); // acknowledged data freed space in tx_buffer
+                    } else if seq::gt(seg_ack, self.snd_nxt) {
+                        // If the ACK acks something not yet sent, send an ACK and drop the segment
                         return self.send_ack(dev);
+                    } else if seg_ack == self.snd_una {
+                        // an ACK carrying nothing new while data is outstanding
+                        // hints that a segment was lost (RFC 5681)
+                        if payload.is_empty()
+                            && !tcph.fin()
+                            && seg_wnd == self.snd_wnd
+                            && self.data_in_flight() > 0
+                        {
+                            self.on_dup_ack(dev)?;
+                        }
+                        // nothing new is acknowledged, but the window may have changed
+                        self.update_window(seg_seq, seg_ack, seg_wnd);
+                    }
+                    // If the ACK is duplicate it can be ignored, the segment text is still processed
+                    match self.state {
+                        State::FinWait1 if self.acks_fin(self.snd_una) => {
+                            // In addition to the processing for the ESTABLISHED state, if
+                            // our FIN is now acknowledged then enter FIN-WAIT-2 and continue
+                            // processing in that state.
+                            self.set_state(State::FinWait2);
+                        }
+                        State::FinWait2 => {
+                            // TODO:
+                            // In addition to the processing for the ESTABLISHED state, if
+                            // the retransmission queue is empty, the user's CLOSE can be
+                            // acknowledged ("ok") but do not delete the TCB.
+                        }
+                        State::Closing => {
+                            // In addition to the processing for the ESTABLISHED state, if
+                            // the ACK acknowledges our FIN then enter the TIME-WAIT state,
+                            // otherwise ignore the segment.
+                            if !self.acks_fin(self.snd_una) {
+                                return Ok(());
+                            }
+                            self.enter_time_wait();
+                        }
+                        State::LastAck if self.acks_fin(self.snd_una) => {
+                            // Data queued before the CLOSE is still acknowledged above.
+                            // If our FIN is now acknowledged, delete the TCB, enter the
+                            // CLOSED state, and return.
+                            self.set_state(State::Closed);
+                        }
+                        _ => {}
                     }
                 }
-                State::FinWait1 => {
-                    // In addition to the processing for the ESTABLISHED state, if
-                    // our FIN is now acknowledged then enter FIN-WAIT-2 and continue
-                    // processing in that state.
-                    self.state = State::FinWait2;
-                }
-                State::FinWait2 => {
-                    // TODO:
-                    // In addition to the processing for the ESTABLISHED state, if
-                    // the retransmission queue is empty, the user's CLOSE can be
-                    // acknowledged ("ok") but do not delete the TCB.
-                }
-                State::Closing => {
-                    // TODO:
-                    // In addition to the processing for the ESTABLISHED state, if
-                    // the ACK acknowledges our FIN then enter the TIME-WAIT state,
-                    // otherwise ignore the segment.
-                    self.state = State::TimeWait;
-                }
-                State::LastAck => {
-                    // The only thing that can arrive in self state is an
-                    // acknowledgment of our FIN.  If our FIN is now acknowledged,
-                    // delete the TCB, enter the CLOSED state, and return.
-                    self.state = State::Closed;
-                }
-                State::TimeWait => {
-                    // TODO:
+                State::TimeWait if tcph.fin() => {
                     // The only thing that can arrive in self state is a
                     // retransmission of the remote FIN.  Acknowledge it, and restart
                     // the 2 MSL timeout.
-                    self.state = State::Closed;
+                    self.timers.start_time_wait(2 * self.msl);
+                    return self.send_ack(dev);
                 }
                 _ => {}
             }
         } else {
             return Ok(());
         }
-        if tcph.urg() {
-            unimplemented!()
+        // check the URG bit: RCV.UP <- max(RCV.UP, SEG.UP), the data itself is delivered in-band
+        if tcph.urg() && matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2) {
+            let seg_up = tcph
+                .sequence_number()
+                .wrapping_add(tcph.urgent_pointer() as u32);
+            if self.rcv_up.is_none_or(|rcv_up| seq::gt(seg_up, rcv_up)) {
+                tracing::debug!("peer sent urgent data up to SEQ={}", seg_up);
+                self.rcv_up = Some(seg_up);
+            }
         }
+        // a segment may carry data and a FIN at once, acknowledge both with a single ACK
+        let mut ack_needed = false;
+        // only an ACK for in-order data that leaves no hole behind may be delayed
+        let mut ack_delayable = false;
+        // the FIN follows the segment text, at SEG.SEQ + SEG.LEN - 1
+        let seg_fin = tcph.sequence_number().wrapping_add(payload.len() as u32);
         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            // process the segment text
+            // process the segment text, keeping only what lies within the receive window
+            let (seg_seq, text) = self.trim_to_window(tcph.sequence_number(), payload);
+            if !text.is_empty() {
+                if seg_seq == self.rcv_nxt {
+                    ack_delayable = self.out_of_order.is_empty();
+                    self.deliver(text);
+                    // the segment may have filled a hole, splice the queued data behind it
+                    self.reassemble();
+                    // readers are woken for all in-order text, independent of the ACK
+                    // being delayed, which honors PSH (RFC 1122, 4.2.2.2) for free
+                    if tcph.psh() {
+                        tracing::trace!("peer pushed data up to SEQ={}", self.rcv_nxt);
+                    }
+                    read_cvar.notify_all();
+                } else {
+                    // there's a hole before this segment, hold it until the gap is filled
+                    tracing::debug!("queueing out-of-order segment: SEQ={}", seg_seq);
+                    self.out_of_order.insert(seg_seq, text.to_vec());
+                    self.out_of_order_segments += 1;
+                    // a FIN right behind text that was kept whole waits with it
+                    if tcph.fin() && seg_seq.wrapping_add(text.len() as u32) == seg_fin {
+                        self.out_of_order_fin = Some(seg_fin);
+                    }
+                }
+            }
             if !payload.is_empty() {
-                self.rx_buffer.extend(payload);
-
-                self.rcv_nxt = self.rcv_nxt.wrapping_add(payload.len() as u32);
-                self.rcv_wnd = self.rx_window() as u16;
-
-                self.send_ack(dev)?;
-                read_cvar.notify_all();
+                // the ACK reflects the next in-order byte, a duplicate ACK signals the hole
+                ack_needed = true;
+            }
+        }
+        if let State::CloseWait | State::Closing | State::LastAck | State::TimeWait = self.state {
+            // the peer's FIN has been seen, so any text is a retransmission: re-ACK, never buffer
+            if !payload.is_empty() {
+                ack_needed = true;
             }
         }
 
-        // SEG.SEQ cannot be validated in CLOSED, LISTEN or SYN-SENT, drop and return
-        if tcph.fin() && !matches!(self.state, State::Closed | State::Listen | State::SynSent) {
+        // SEG.SEQ cannot be validated in CLOSED, LISTEN or SYN-SENT, drop and return,
+        // and states past the peer's FIN have no FIN left to consume
+        let fin_expected = matches!(
+            self.state,
+            State::SynRcvd | State::Estab | State::FinWait1 | State::FinWait2
+        );
+        // the FIN is only consumed once all the text before it has been, i.e. when
+        // it is the next byte expected. The segment filling a hole may reach a held FIN.
+        let fin_in_order =
+            (tcph.fin() && seg_fin == self.rcv_nxt) || self.out_of_order_fin == Some(self.rcv_nxt);
+        if fin_expected && tcph.fin() && !fin_in_order {
+            // text before the FIN is missing or didn't fit the window
+            tracing::debug!(
+                "holding back FIN at SEQ={}, expected {}",
+                seg_fin,
+                self.rcv_nxt
+            );
+            ack_needed = true;
+        }
+        if fin_expected && fin_in_order {
+            self.out_of_order_fin = None;
             self.rcv_nxt = self.rcv_nxt.wrapping_add(1); // FIN bit takes 1 seq number
-            self.send_ack(dev)?;
+            ack_needed = true;
             read_cvar.notify_all(); // connection is half-closed, notify
 
             // send any remaining data?
             match self.state {
                 State::SynRcvd | State::Estab => {
-                    self.state = State::CloseWait;
+                    self.set_state(State::CloseWait);
                 }
                 State::FinWait1 => {
-                    // TODO:
                     // If our FIN has been ACKed (perhaps in this segment), then
                     // enter TIME-WAIT, start the time-wait timer, turn off the other
                     // timers; otherwise enter the CLOSING state.
+                    if self.acks_fin(self.snd_una) {
+                        self.enter_time_wait();
+                    } else {
+                        self.set_state(State::Closing);
+                    }
                 }
                 State::FinWait2 => {
-                    // TODO:
                     // Enter the TIME-WAIT state.  Start the time-wait timer, turn
                     // off the other timers.
+                    self.enter_time_wait();
                 }
-                State::TimeWait => {
-                    // TODO:
-                    // Remain in the TIME-WAIT state.  Restart the 2 MSL time-wait
-                    // timeout and return.
-                }
+                _ => unreachable!(),
+            }
+        }
 
-                // Remain in other states
-                _ => {}
+        if ack_needed {
+            // a FIN, received or reached by filling the hole before it, is acknowledged now
+            let delayed = ack_delayable
+                && !tcph.fin()
+                && !(fin_expected && fin_in_order)
+                && self.delay_ack(payload.len());
+            if !delayed {
+                self.send_ack(dev)?;
             }
         }
         Ok(())
     }
 
+    // hold back the ACK for `len` new bytes, returns false if it has to go out now
+    fn delay_ack(&mut self, len: usize) -> bool {
+        self.unacked_bytes += len;
+        // acknowledge at least every second full-sized segment (RFC 5681, 4.2)
+        if self.ack_delay.is_zero() || self.unacked_bytes >= 2 * self.mss as usize {
+            return false;
+        }
+        self.timers.start_delayed_ack(self.ack_delay);
+        true
+    }
+
+    // trim the text of a segment to RCV.NXT =< SEQ < RCV.NXT+RCV.WND
+    fn trim_to_window<'a>(&self, seg_seq: u32, payload: &'a [u8]) -> (u32, &'a [u8]) {
+        // drop the bytes that have already been received
+        let start = if seq::lt(seg_seq, self.rcv_nxt) {
+            (self.rcv_nxt.wrapping_sub(seg_seq) as usize).min(payload.len())
+        } else {
+            0
+        };
+        let seg_seq = seg_seq.wrapping_add(start as u32);
+        // drop the bytes that don't fit into the advertised window
+        let rcv_win = self.rcv_nxt.wrapping_add(self.rcv_wnd);
+        let room = if seq::lt(seg_seq, rcv_win) {
+            rcv_win.wrapping_sub(seg_seq) as usize
+        } else {
+            0
+        };
+        let end = payload.len().min(start + room);
+        (seg_seq, &payload[start..end])
+    }
+
+    // append in-order data to the receive buffer. Whatever doesn't fit isn't
+    // acknowledged, the peer retransmits it once the application made room.
+    fn deliver(&mut self, data: &[u8]) {
+        let n = if self.rx_shutdown {
+            data.len()
+        } else {
+            self.rx_buffer.write(data)
+        };
+        if n < data.len() {
+            tracing::debug!("receive buffer full, dropping {} bytes", data.len() - n);
+        }
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(n as u32);
+        self.bytes_received += n as u64;
+        // the right edge of the window stays where it was
+        self.rcv_wnd = self.rcv_wnd.saturating_sub(n as u32);
+        self.update_rcv_wnd();
+        if self.rcv_wnd == 0 {
+            tracing::debug!("receive window closed, waiting for the application to read");
+        }
+    }
+
+    // move queued out-of-order segments that became contiguous into the receive buffer
+    fn reassemble(&mut self) {
+        while let Some(seg_seq) = self
+            .out_of_order
+            .keys()
+            .copied()
+            .find(|&seg_seq| seq::leq(seg_seq, self.rcv_nxt))
+        {
+            let data = self.out_of_order.remove(&seg_seq).unwrap();
+            // skip whatever part of the segment has already been delivered
+            let delivered = self.rcv_nxt.wrapping_sub(seg_seq) as usize;
+            if delivered < data.len() {
+                self.deliver(&data[delivered..]);
+            }
+        }
+    }
+
+    // process an ACK with SND.UNA < SEG.ACK =< SND.NXT
+    fn on_new_ack(
+        &mut self,
+        dev: &mut impl PacketDevice,
+        seg_seq: u32,
+        seg_ack: u32,
+        seg_wnd: u32,
+        ts_ecr: Option<u32>,
+    ) -> io::Result<()> {
+        let ack_idx = seg_ack.wrapping_sub(self.snd_una) as usize;
+        // remove everything up to seg_ack
+        self.tx_buffer.consume(ack_idx.min(self.tx_buffer.len()));
+        self.snd_una = seg_ack;
+        self.retransmits = 0;
+        self.dup_acks = 0;
+        self.sacked.retain(|&(_, right)| seq::gt(right, seg_ack));
+        let partial_ack = self.on_cwnd_ack(ack_idx as u32, seg_ack);
+
+        // cancel the retransmit timer/s associated with the snd_una
+        let mut last_sent: Option<Instant> = None;
+        let mut ambiguous = false;
+        self.timers.find_rto_by_ack(seg_ack, |seq, rto_entry| {
+            tracing::debug!(
+                "RTO for seq {} with payload_len {} acked",
+                seq,
+                rto_entry.payload_len()
+            );
+            last_sent = last_sent.max(Some(rto_entry.sent_at()));
+            ambiguous |= rto_entry.retransmitted();
+        });
+        // measure against the most recently sent of the acknowledged segments.
+        // Karn's algorithm: when a retransmitted segment is acknowledged it is
+        // unknown which transmission the ACK is for, so no sample is taken and
+        // the backed-off RTO is kept.
+        // An echoed timestamp identifies the transmission, so it is always usable.
+        if let Some(ts_ecr) = ts_ecr {
+            let rtt = self.ts_now().wrapping_sub(ts_ecr);
+            self.on_rtt_sample(Duration::from_millis(rtt as u64));
+        } else if let Some(sent_at) = last_sent
+            && !ambiguous
+        {
+            self.on_rtt_sample(sent_at.elapsed());
+        }
+
+        self.update_window(seg_seq, seg_ack, seg_wnd);
+        if partial_ack {
+            // NewReno: the next hole is known lost as well, repair it right away
+            self.retransmit_una(dev)?;
+        }
+        Ok(())
+    }
+
+    // grow or, in fast recovery, deflate the congestion window on `acked` new bytes.
+    // Returns whether the ACK was a partial ACK during fast recovery.
+    fn on_cwnd_ack(&mut self, acked: u32, seg_ack: u32) -> bool {
+        let smss = self.mss as u32;
+        if let Some(recover) = self.recover {
+            if seq::geq(seg_ack, recover) {
+                // full ACK, everything outstanding when the loss was detected is acknowledged
+                self.cwnd = self.ssthresh;
+                self.recover = None;
+                return false;
+            }
+            // partial ACK, deflate by the amount acknowledged and add back one SMSS
+            self.cwnd = self.cwnd.saturating_sub(acked);
+            if acked >= smss {
+                self.cwnd += smss;
+            }
+            return true;
+        }
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd = self.cwnd.saturating_add(acked.min(smss));
+        } else {
+            // congestion avoidance, about one SMSS per RTT
+            self.cwnd = self
+                .cwnd
+                .saturating_add((smss * smss / self.cwnd.max(1)).max(1));
+        }
+        false
+    }
+
+    // ssthresh = max(FlightSize / 2, 2*SMSS)
+    fn loss_ssthresh(&self) -> u32 {
+        (self.data_in_flight() as u32 / 2).max(2 * self.mss as u32)
+    }
+
+    fn on_dup_ack(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        self.dup_acks += 1;
+        self.dup_acks_received += 1;
+        if self.recover.is_some() {
+            // each further duplicate means a segment left the network, inflate the window
+            self.cwnd = self.cwnd.saturating_add(self.mss as u32);
+            return Ok(());
+        }
+        if self.dup_acks != DUP_ACK_THRESHOLD {
+            return Ok(());
+        }
+        // fast retransmit: resend the segment at SND.UNA without waiting for the RTO,
+        // then enter fast recovery
+        tracing::debug!("fast retransmit: SEQ={}", self.snd_una);
+        self.ssthresh = self.loss_ssthresh();
+        self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD * self.mss as u32;
+        self.recover = Some(self.snd_nxt);
+        self.retransmit_una(dev)
+    }
+
+    // resend the oldest unacknowledged segment and restart its timer
+    fn retransmit_una(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        if self.data_in_flight() == 0 {
+            // only our FIN is outstanding, it keeps its own timer
+            return Ok(());
+        }
+        let seg_size = self
+            .timers
+            .cancel_rto(self.snd_una)
+            .map_or(self.data_in_flight(), |entry| entry.payload_len())
+            .min(self.data_in_flight())
+            .min(self.mss as usize);
+        if seg_size == 0 {
+            return Ok(());
+        }
+        let payload: Vec<u8> = self.tx_buffer.range(..seg_size).copied().collect();
+        let flags = TcpFlags {
+            psh: true,
+            ..Default::default()
+        };
+        self.send(dev, self.snd_una, Some(self.rcv_nxt), &flags, &payload)?;
+        self.segments_retransmitted += 1;
+        self.timers
+            .restart_rto(self.snd_una, flags, self.rto, seg_size);
+        Ok(())
+    }
+
+    // update SRTT, RTTVAR and the RTO from a round-trip measurement (RFC 6298)
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                // SRTT <- R, RTTVAR <- R/2
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|, beta = 1/4
+                // SRTT <- (1 - alpha) * SRTT + alpha * R', alpha = 1/8
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        // RTO <- SRTT + K*RTTVAR, K = 4
+        let rto = self.srtt.unwrap_or_default() + self.rttvar * 4;
+        self.rto = self.clamp_rto(rto);
+        tracing::trace!(
+            "RTT sample {:?}: SRTT={:?}, RTO={:?}",
+            rtt,
+            self.srtt,
+            self.rto
+        );
+    }
+
+    fn clamp_rto(&self, rto: Duration) -> Duration {
+        rto.clamp(self.rto_min, self.rto_max.max(self.rto_min))
+    }
+
+    // updating the window from send sequence space
+    fn update_window(&mut self, seg_seq: u32, seg_ack: u32, seg_wnd: u32) {
+        if seq::lt(self.snd_wl1, seg_seq)
+            || (self.snd_wl1 == seg_seq && seq::leq(self.snd_wl2, seg_ack))
+        {
+            if seg_wnd == 0 && self.snd_wnd != 0 {
+                tracing::debug!("peer closed its window");
+            }
+            self.snd_wnd = seg_wnd;
+            self.snd_wl1 = seg_seq;
+            self.snd_wl2 = seg_ack;
+        }
+        if self.snd_wnd > 0 && self.timers.persist_running() {
+            // the window reopened, resume normal transmission from SND.UNA
+            tracing::debug!("peer reopened its window: WND={}", self.snd_wnd);
+            self.timers.cancel_persist();
+            self.timers.cancel_all_rto();
+            self.persist_interval = self.rto;
+            self.snd_nxt = self.snd_una;
+        }
+    }
+
     fn process_syn_sent(
         &mut self,
-        dev: &mut device::TunDevice,
+        dev: &mut impl PacketDevice,
         hdr: &etherparse::TcpHeaderSlice,
     ) -> io::Result<()> {
         let seg_ack = hdr.acknowledgment_number();
-        if seg_ack <= self.iss || seg_ack > self.snd_nxt {
-            if hdr.rst() {
+        // first check the ACK bit, a bare SYN of a simultaneous open carries none
+        if hdr.ack() {
+            if seq::leq(seg_ack, self.iss) || seq::gt(seg_ack, self.snd_nxt) {
+                if hdr.rst() {
+                    return Ok(());
+                }
+                return self.send_rst(dev, seg_ack);
+            }
+            if !(seq::geq(seg_ack, self.snd_una) && seq::leq(seg_ack, self.snd_nxt)) {
                 return Ok(());
             }
-            return self.send_rst(dev, seg_ack);
         }
-
-        match seg_ack >= self.snd_una && seg_ack <= self.snd_nxt {
-            true => {
-                if hdr.rst() {
-                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
-                }
+        if hdr.rst() {
+            // a RST is only acceptable if it acknowledges our SYN
+            if !hdr.ack() {
+                return Ok(());
             }
-            false => return Ok(()),
+            // nobody listens on the remote port
+            self.set_state(State::Closed);
+            return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
         }
 
         if hdr.syn() {
-            self.rcv_nxt = hdr.sequence_number() + 1;
+            self.on_syn_options(&TcpOptions::parse(hdr.options()).unwrap_or_default());
+            self.rcv_nxt = hdr.sequence_number().wrapping_add(1);
             self.irs = hdr.sequence_number();
             if hdr.ack() {
                 self.snd_una = seg_ack;
             }
-            if self.snd_una > self.iss {
-                self.state = State::Estab;
+            if seq::gt(self.snd_una, self.iss) {
+                // our SYN has been acknowledged, stop retransmitting it
+                // Karn: a retransmitted SYN can't be timed
+                if let Some(entry) = self.timers.cancel_rto(self.iss)
+                    && !entry.retransmitted()
+                {
+                    self.on_rtt_sample(entry.sent_at().elapsed());
+                }
+                self.set_state(State::Estab);
+                self.established_at = Some(Instant::now());
+                self.snd_wnd = hdr.window_size() as u32;
+                self.snd_wl1 = hdr.sequence_number();
+                self.snd_wl2 = seg_ack;
                 self.send(
                     dev,
                     self.snd_nxt,
@@ -634,6 +2210,20 @@ impl Tcb {
                     &TcpFlags::default(),
                     &[],
                 )?;
+            } else {
+                // simultaneous open: both SYNs crossed, answer with a SYN-ACK
+                // <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>
+                tracing::debug!("simultaneous open with {:?}", self.remote_addr);
+                self.set_state(State::SynRcvd);
+                self.syn_rcvd_at = Some(Instant::now());
+                self.snd_wnd = hdr.window_size() as u32;
+                self.snd_wl1 = hdr.sequence_number();
+                self.snd_wl2 = seg_ack;
+                let flags = TcpFlags {
+                    syn: true,
+                    ..Default::default()
+                };
+                self.send(dev, self.iss, Some(self.rcv_nxt), &flags, &[])?;
             }
         }
 
@@ -642,22 +2232,39 @@ impl Tcb {
 
     fn process_close(
         &mut self,
-        dev: &mut device::TunDevice,
+        dev: &mut impl PacketDevice,
         hdr: &etherparse::TcpHeaderSlice,
         payload: &[u8],
     ) -> io::Result<()> {
         if !hdr.rst() {
             match hdr.ack() {
-                true => return self.send_rst(dev, hdr.sequence_number()),
+                // <SEQ=SEG.ACK><CTL=RST>
+                true => return self.send_rst(dev, hdr.acknowledgment_number()),
                 false => {
-                    return self.send_rst_ack(dev, hdr.sequence_number(), payload.len() as u32);
+                    let seg_len = Self::segment_length(hdr, payload.len());
+                    return self.send_rst_ack(dev, hdr.sequence_number(), seg_len);
                 }
             }
         }
         Ok(())
     }
 
-    fn send_ack(&mut self, dev: &mut device::TunDevice) -> io::Result<()> {
+    /// Answers a segment that matches neither a connection nor a listener as
+    /// if it arrived for a CLOSED TCB, with a RST
+    pub fn reject(
+        dev: &mut impl PacketDevice,
+        tuple: Tuple,
+        hdr: &etherparse::TcpHeaderSlice,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut tcb = Tcb::new(tuple.local_ip());
+        tcb.remote_addr = Some(tuple.remote_ip());
+        tcb.tuple = Some(tuple);
+        tcb.process_close(dev, hdr, payload)
+    }
+
+    fn send_ack(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        self.ack_sent();
         self.send(
             dev,
             self.snd_nxt,
@@ -668,7 +2275,18 @@ impl Tcb {
         Ok(())
     }
 
-    fn send_rst(&mut self, dev: &mut device::TunDevice, seq: u32) -> io::Result<()> {
+    // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK> in response to a suspicious segment (RFC 5961)
+    fn send_challenge_ack(&mut self, dev: &mut impl PacketDevice) -> io::Result<()> {
+        self.send_ack(dev)
+    }
+
+    // an ACK went out, nothing is left to acknowledge later
+    fn ack_sent(&mut self) {
+        self.timers.cancel_delayed_ack();
+        self.unacked_bytes = 0;
+    }
+
+    fn send_rst(&mut self, dev: &mut impl PacketDevice, seq: u32) -> io::Result<()> {
         self.rcv_wnd = 0;
         let flags = TcpFlags {
             rst: true,
@@ -680,7 +2298,7 @@ impl Tcb {
 
     fn send_rst_ack(
         &mut self,
-        dev: &mut device::TunDevice,
+        dev: &mut impl PacketDevice,
         seq: u32,
         seg_len: u32,
     ) -> io::Result<()> {
@@ -699,12 +2317,13 @@ impl Tcb {
         seq: u32,
         ack: Option<u32>,
         flags: &TcpFlags,
+        payload_len: usize,
     ) -> etherparse::TcpHeader {
         let mut th = etherparse::TcpHeader::new(
             self.local_addr.port(),
             self.remote_addr.unwrap().port(),
             seq,
-            self.rcv_wnd,
+            self.advertised_wnd(flags.syn),
         );
         if let Some(ack_num) = ack {
             th.acknowledgment_number = ack_num;
@@ -714,13 +2333,33 @@ impl Tcb {
         th.fin = flags.fin;
         th.psh = flags.psh;
         th.rst = flags.rst;
+        // MSS and window scale are only announced during the handshake
+        let options = TcpOptions {
+            mss: flags.syn.then(|| self.local_mss()),
+            sack_permitted: flags.syn && self.sack_permitted,
+            sack: if self.sack_permitted && !flags.syn && !flags.rst && ack.is_some() {
+                self.sack_blocks(payload_len)
+            } else {
+                Vec::new()
+            },
+            window_scale: (flags.syn && self.window_scaling).then_some(self.rcv_wnd_shift),
+            timestamp: (self.timestamps && !flags.rst).then(|| {
+                // TSecr is only valid when the ACK bit is set
+                (
+                    self.ts_now(),
+                    if ack.is_some() { self.ts_recent } else { 0 },
+                )
+            }),
+        };
+        th.set_options(&options.elements())
+            .expect("options fit in the TCP header");
 
         th
     }
 
     fn send(
         &self,
-        dev: &mut device::TunDevice,
+        dev: &mut impl PacketDevice,
         seq: u32,
         ack: Option<u32>,
         flags: &TcpFlags,
@@ -731,24 +2370,910 @@ impl Tcb {
             .expect("tuple has to exist when calling send()");
 
         // calculate checksum and length
-        let builder = match cp {
-            Tuple::V4(cp_v4) => etherparse::PacketBuilder::ipv4(
-                cp_v4.local.ip().octets(),
-                cp_v4.remote.ip().octets(),
-                HOP_LIMIT,
+        // length, protocol and checksum of the IP header are filled in on write
+        let ip = match cp {
+            Tuple::V4(cp_v4) => etherparse::IpHeaders::Ipv4(
+                etherparse::Ipv4Header {
+                    source: cp_v4.local.ip().octets(),
+                    destination: cp_v4.remote.ip().octets(),
+                    time_to_live: self.ttl,
+                    // six bits always fit
+                    dscp: etherparse::IpDscp::try_new(self.tos >> 2).unwrap(),
+                    ..Default::default()
+                },
+                Default::default(),
             ),
-            Tuple::V6(cp_v6) => etherparse::PacketBuilder::ipv6(
-                cp_v6.local.ip().octets(),
-                cp_v6.remote.ip().octets(),
-                HOP_LIMIT,
+            Tuple::V6(cp_v6) => etherparse::IpHeaders::Ipv6(
+                etherparse::Ipv6Header {
+                    source: cp_v6.local.ip().octets(),
+                    destination: cp_v6.remote.ip().octets(),
+                    hop_limit: self.ttl,
+                    traffic_class: self.tos,
+                    ..Default::default()
+                },
+                Default::default(),
             ),
+        };
+        let builder = etherparse::PacketBuilder::ip(ip).tcp_header(self.build_tcp_header(
+            seq,
+            ack,
+            flags,
+            payload.len(),
+        ));
+
+        SEND_BUF.with_borrow_mut(|datagram| {
+            datagram.clear();
+            datagram.reserve(builder.size(payload.len()));
+            match builder.write(datagram, payload) {
+                Ok(_) => dev.send(datagram.as_slice()),
+                Err(_) => Err(std::io::Error::other("Packet serialization failed")),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::{TcpHeader, TcpHeaderSlice, TcpOptionElement};
+
+    use super::*;
+    use crate::loopback::{LoopbackDevice, LoopbackPeer};
+
+    const ISS: u32 = 1000;
+    const IRS: u32 = 5000;
+
+    // an ESTABLISHED TCB with data in flight, its segments land in the peer
+    fn established() -> (Tcb, LoopbackDevice, LoopbackPeer) {
+        established_from(ISS)
+    }
+
+    // like `established`, with our sequence numbers starting at `iss`
+    fn established_from(iss: u32) -> (Tcb, LoopbackDevice, LoopbackPeer) {
+        let (mut dev, peer) = LoopbackDevice::pair().unwrap();
+        let local: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let remote: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let mut tcb = Tcb::with_options(
+            local,
+            SocketOptions {
+                tx_buffer: 4096,
+                ..Default::default()
+            },
+        );
+        tcb.remote_addr = Some(remote);
+        tcb.tuple = Some(Tuple::new(local, remote));
+        tcb.iss = iss;
+        tcb.snd_una = iss.wrapping_add(1);
+        tcb.snd_nxt = iss.wrapping_add(1);
+        tcb.irs = IRS;
+        tcb.rcv_nxt = IRS.wrapping_add(1);
+        tcb.snd_wnd = 8192;
+        tcb.snd_wl1 = IRS;
+        tcb.snd_wl2 = iss;
+        tcb.set_state(State::Estab);
+        tcb.write(&[7; 3000]).unwrap();
+        tcb.on_tick(&mut dev).unwrap();
+        assert!(seq::lt(tcb.snd_una, tcb.snd_nxt));
+        (tcb, dev, peer)
+    }
+
+    // a pure ACK from the peer carrying `options`
+    fn ack(ack: u32, options: &[TcpOptionElement]) -> Vec<u8> {
+        let mut hdr = TcpHeader::new(40000, 8080, IRS.wrapping_add(1), 8192);
+        hdr.ack = true;
+        hdr.acknowledgment_number = ack;
+        hdr.set_options(options).unwrap();
+        hdr.to_bytes().to_vec()
+    }
+
+    // a segment from the peer at `seq` acknowledging `ack`, carrying a FIN if `fin`
+    fn from_peer(seq: u32, ack: u32, fin: bool) -> Vec<u8> {
+        let mut hdr = TcpHeader::new(40000, 8080, seq, 8192);
+        hdr.ack = true;
+        hdr.fin = fin;
+        hdr.acknowledgment_number = ack;
+        hdr.to_bytes().to_vec()
+    }
+
+    fn deliver(tcb: &mut Tcb, dev: &mut LoopbackDevice, seg: &[u8]) {
+        let seg = TcpHeaderSlice::from_slice(seg).unwrap();
+        tcb.on_segment(dev, &seg, &[], &Condvar::new(), &Condvar::new())
+            .unwrap();
+    }
+
+    // `text` from the peer at `seq`, acknowledging nothing new
+    fn deliver_text(tcb: &mut Tcb, dev: &mut LoopbackDevice, seq: u32, text: &[u8]) {
+        let seg = from_peer(seq, tcb.snd_una, false);
+        let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+        tcb.on_segment(dev, &seg, text, &Condvar::new(), &Condvar::new())
+            .unwrap();
+    }
+
+    // the TCP header of the last segment the TCB sent
+    fn last_sent(peer: &LoopbackPeer) -> TcpHeader {
+        let mut last = None;
+        while let Ok(pkt) = peer.capture(Some(Duration::from_millis(1))) {
+            last = Some(pkt);
+        }
+        let pkt = last.expect("nothing was sent");
+        let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+        let Some(etherparse::TransportSlice::Tcp(tcp)) = pkt.transport else {
+            panic!("expected a TCP segment");
+        };
+        tcp.to_header()
+    }
+
+    // acknowledge the data as it goes out until none is left, an idle connection
+    fn ack_everything(tcb: &mut Tcb, dev: &mut LoopbackDevice) {
+        while !tcb.tx_is_empty() {
+            deliver(tcb, dev, &ack(tcb.snd_nxt, &[]));
+            tcb.on_tick(dev).unwrap();
+        }
+    }
+
+    // acknowledge our data as it goes out, from the peer at `seq`, until the FIN follows it
+    fn send_until_fin(tcb: &mut Tcb, dev: &mut LoopbackDevice, seq: u32) -> u32 {
+        while tcb.fin_seq.is_none() {
+            assert!(tcb.next_deadline().is_some());
+            deliver(tcb, dev, &from_peer(seq, tcb.snd_nxt, false));
+            tcb.on_tick(dev).unwrap();
+        }
+        tcb.fin_seq.unwrap()
+    }
+
+    // the timestamps option the way the peer pads it, trailing NOPs pad it further
+    fn timestamp(tsval: u32, tsecr: u32, nops: usize) -> Vec<TcpOptionElement> {
+        let mut options = vec![
+            TcpOptionElement::Noop,
+            TcpOptionElement::Noop,
+            TcpOptionElement::Timestamp(tsval, tsecr),
+        ];
+        options.extend(vec![TcpOptionElement::Noop; nops]);
+        options
+    }
+
+    // sequence variables, congestion state and retransmission bookkeeping
+    type Observed = (State, [u32; 7], (u32, u32), (usize, u32, u32, usize));
+
+    fn observe(tcb: &Tcb) -> Observed {
+        (
+            tcb.state,
+            [
+                tcb.snd_una,
+                tcb.snd_nxt,
+                tcb.snd_wnd,
+                tcb.snd_wl1,
+                tcb.snd_wl2,
+                tcb.rcv_nxt,
+                tcb.rcv_wnd,
+            ],
+            (tcb.cwnd, tcb.ssthresh),
+            (
+                tcb.tx_buffer.len(),
+                tcb.retransmits,
+                tcb.dup_acks,
+                tcb.timers.rto_count(),
+            ),
+        )
+    }
+
+    #[test]
+    fn fast_and_slow_path_agree_on_a_pure_ack() {
+        let (mut fast, mut fast_dev, _fast_peer) = established();
+        let (mut slow, mut slow_dev, _slow_peer) = established();
+        assert_eq!(observe(&fast), observe(&slow));
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+
+        // the fast path takes a segment without options, padding sends it down the slow path
+        // acknowledge everything in flight, which lets both send more
+        let seg_ack = fast.snd_nxt;
+        let bare = ack(seg_ack, &[]);
+        let padded = ack(seg_ack, &vec![TcpOptionElement::Noop; 4]);
+        let bare = TcpHeaderSlice::from_slice(&bare).unwrap();
+        let padded = TcpHeaderSlice::from_slice(&padded).unwrap();
+        fast.on_segment(&mut fast_dev, &bare, &[], &read_cvar, &write_cvar)
+            .unwrap();
+        slow.on_segment(&mut slow_dev, &padded, &[], &read_cvar, &write_cvar)
+            .unwrap();
+
+        assert_eq!(fast.snd_una, seg_ack);
+        assert_eq!((fast.fast_path_acks, slow.fast_path_acks), (1, 0));
+        assert_eq!(observe(&fast), observe(&slow));
+    }
+
+    #[test]
+    fn partial_ack_up_to_the_fin_keeps_its_timer() {
+        let (mut tcb, mut dev, _peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        tcb.init_closing();
+        // acknowledge the data as it goes out, until the FIN follows it
+        while tcb.fin_seq.is_none() {
+            let seg = ack(tcb.snd_nxt, &[]);
+            let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+            tcb.on_segment(&mut dev, &seg, &[], &read_cvar, &write_cvar)
+                .unwrap();
+            tcb.on_tick(&mut dev).unwrap();
         }
-        .tcp_header(self.build_tcp_header(seq, ack, flags));
+        let fin_seq = tcb.fin_seq.unwrap();
+        // in fast recovery an ACK of all the data, but not the FIN, is a partial ACK
+        tcb.recover = Some(tcb.snd_nxt);
+        let partial = ack(fin_seq, &[]);
+        let partial = TcpHeaderSlice::from_slice(&partial).unwrap();
+        tcb.on_segment(&mut dev, &partial, &[], &read_cvar, &write_cvar)
+            .unwrap();
+
+        assert_eq!(tcb.snd_una, fin_seq);
+        assert_eq!(tcb.timers.rto_count(), 1);
+        assert!(tcb.next_deadline().is_some());
+    }
+
+    #[test]
+    fn fin_queued_behind_data_goes_out_in_closing() {
+        let (mut tcb, mut dev, _peer) = established();
+        tcb.init_closing();
+        assert_eq!(tcb.fin_seq, None);
+        // the peer closes too while our data, and so our FIN, is still queued
+        let fin = from_peer(IRS.wrapping_add(1), tcb.snd_una, true);
+        deliver(&mut tcb, &mut dev, &fin);
+        assert_eq!(tcb.state, State::Closing);
+
+        // our FIN follows once the data is acknowledged, the peer's FIN took a seq number
+        let fin_seq = send_until_fin(&mut tcb, &mut dev, IRS.wrapping_add(2));
+        assert_eq!(tcb.snd_nxt, fin_seq.wrapping_add(1));
+        assert!(tcb.next_deadline().is_some());
+    }
+
+    #[test]
+    fn last_ack_counts_the_fin_once() {
+        let (mut tcb, mut dev, _peer) = established();
+        let fin = from_peer(IRS.wrapping_add(1), tcb.snd_una, true);
+        deliver(&mut tcb, &mut dev, &fin);
+        assert_eq!(tcb.state, State::CloseWait);
+        tcb.init_closing();
+        assert_eq!(tcb.state, State::LastAck);
+
+        // the FIN takes exactly one sequence number, further ticks don't resend it
+        let fin_seq = send_until_fin(&mut tcb, &mut dev, IRS.wrapping_add(2));
+        assert_eq!(tcb.snd_nxt, fin_seq.wrapping_add(1));
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(tcb.snd_nxt, fin_seq.wrapping_add(1));
+
+        // so the ACK of the FIN is SND.NXT, and it closes the connection
+        let ack = from_peer(IRS.wrapping_add(2), tcb.snd_nxt, false);
+        deliver(&mut tcb, &mut dev, &ack);
+        assert_eq!(tcb.state, State::Closed);
+    }
+
+    #[test]
+    fn time_wait_acks_a_retransmitted_fin() {
+        let (mut tcb, mut dev, peer) = established();
+        tcb.init_closing();
+        let fin_seq = send_until_fin(&mut tcb, &mut dev, IRS.wrapping_add(1));
+        let ack = from_peer(IRS.wrapping_add(1), fin_seq.wrapping_add(1), false);
+        deliver(&mut tcb, &mut dev, &ack);
+        assert_eq!(tcb.state, State::FinWait2);
+        let fin = from_peer(IRS.wrapping_add(1), fin_seq.wrapping_add(1), true);
+        deliver(&mut tcb, &mut dev, &fin);
+        assert_eq!(tcb.state, State::TimeWait);
+        let expiry = tcb.timers.time_wait_expiry().unwrap();
+        last_sent(&peer);
+
+        // our ACK of the FIN got lost, the peer sends it again
+        std::thread::sleep(Duration::from_millis(5));
+        deliver(&mut tcb, &mut dev, &fin);
+        let reply = last_sent(&peer);
+        assert!(reply.ack && !reply.fin);
+        assert_eq!(reply.acknowledgment_number, IRS.wrapping_add(2));
+        assert_eq!(tcb.state, State::TimeWait);
+        assert!(tcb.timers.time_wait_expiry().unwrap() > expiry);
+    }
+
+    #[test]
+    fn rst_off_rcv_nxt_gets_a_challenge_ack() {
+        let (mut tcb, mut dev, peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let rst = |seq| {
+            let mut hdr = TcpHeader::new(40000, 8080, seq, 8192);
+            hdr.rst = true;
+            hdr.to_bytes()
+        };
+        last_sent(&peer);
+
+        // in the window, but not at RCV.NXT: the connection survives and is challenged
+        let blind = rst(IRS.wrapping_add(100));
+        let blind = TcpHeaderSlice::from_slice(&blind).unwrap();
+        tcb.on_segment(&mut dev, &blind, &[], &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(tcb.state, State::Estab);
+        let challenge = last_sent(&peer);
+        assert!(challenge.ack && !challenge.rst);
+        assert_eq!(challenge.sequence_number, tcb.snd_nxt);
+        assert_eq!(challenge.acknowledgment_number, tcb.rcv_nxt);
+
+        // the peer's answer at RCV.NXT does reset it
+        let genuine = rst(tcb.rcv_nxt);
+        let genuine = TcpHeaderSlice::from_slice(&genuine).unwrap();
+        let err = tcb
+            .on_segment(&mut dev, &genuine, &[], &read_cvar, &write_cvar)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(tcb.state, State::Closed);
+    }
+
+    #[test]
+    fn in_window_syn_gets_a_challenge_ack() {
+        let (mut tcb, mut dev, peer) = established();
+        last_sent(&peer);
+        let before = observe(&tcb);
+
+        // a SYN in the window, as a restarted peer or a blind attacker would send it
+        let mut syn = TcpHeader::new(40000, 8080, IRS.wrapping_add(10), 8192);
+        syn.syn = true;
+        deliver(&mut tcb, &mut dev, &syn.to_bytes());
+
+        assert_eq!(observe(&tcb), before);
+        let challenge = last_sent(&peer);
+        assert!(challenge.ack && !challenge.syn && !challenge.rst);
+        assert_eq!(challenge.acknowledgment_number, tcb.rcv_nxt);
+    }
+
+    #[test]
+    fn keepalive_tells_a_live_peer_from_a_dead_one() {
+        let (mut tcb, mut dev, peer) = established();
+        ack_everything(&mut tcb, &mut dev);
+        tcb.set_keepalive(Some(Keepalive {
+            idle: Duration::from_millis(5),
+            interval: Duration::from_millis(5),
+            probes: 2,
+        }));
+        last_sent(&peer);
+
+        // idle long enough: a probe goes out, one byte before SND.NXT
+        std::thread::sleep(Duration::from_millis(10));
+        tcb.on_tick(&mut dev).unwrap();
+        let probe = last_sent(&peer);
+        assert_eq!(probe.sequence_number, tcb.snd_nxt.wrapping_sub(1));
+        assert_eq!(tcb.keepalive_probes, 1);
+
+        // the peer answers, so it is alive and the probe count starts over
+        let answer = ack(tcb.snd_nxt, &[]);
+        deliver(&mut tcb, &mut dev, &answer);
+        assert_eq!(tcb.keepalive_probes, 0);
+
+        // then it goes silent: after the last unanswered probe the connection times out
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let err = loop {
+            assert!(Instant::now() < deadline, "keepalive never gave up");
+            std::thread::sleep(Duration::from_millis(5));
+            if let Err(e) = tcb.on_tick(&mut dev) {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(tcb.state, State::Closed);
+    }
+
+    #[test]
+    fn ack_data_and_fin_in_one_segment() {
+        let (mut tcb, mut dev, peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let acked = tcb.snd_nxt;
+        let seg = from_peer(IRS.wrapping_add(1), acked, true);
+        let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+        tcb.on_segment(&mut dev, &seg, b"bye", &read_cvar, &write_cvar)
+            .unwrap();
+
+        // the ACK, the text and the FIN are each processed
+        assert_eq!(tcb.snd_una, acked);
+        assert_eq!(tcb.state, State::CloseWait);
+        assert_eq!(tcb.rcv_nxt, IRS.wrapping_add(5));
+        let mut buf = [0u8; 8];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"bye");
+        assert!(tcb.fin_received());
+        assert_eq!(last_sent(&peer).acknowledgment_number, IRS.wrapping_add(5));
+    }
+
+    #[test]
+    fn text_after_the_peer_fin_is_reacked_not_buffered() {
+        let (mut tcb, mut dev, peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let fin = from_peer(IRS.wrapping_add(1), tcb.snd_una, true);
+        let fin = TcpHeaderSlice::from_slice(&fin).unwrap();
+        tcb.on_segment(&mut dev, &fin, b"abc", &read_cvar, &write_cvar)
+            .unwrap();
+        tcb.init_closing();
+        assert_eq!(tcb.state, State::LastAck);
+        let rcv_nxt = tcb.rcv_nxt;
+        last_sent(&peer);
+
+        // our ACK got lost, the peer sends its text and FIN again
+        tcb.on_segment(&mut dev, &fin, b"abc", &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(tcb.state, State::LastAck);
+        assert_eq!(tcb.rcv_nxt, rcv_nxt);
+        assert_eq!(last_sent(&peer).acknowledgment_number, rcv_nxt);
+        let mut buf = [0u8; 8];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 3);
+        assert!(tcb.rx_is_empty());
+    }
+
+    #[test]
+    fn fin_takes_no_window() {
+        let (mut tcb, mut dev, _peer) = established();
+        tcb.init_closing();
+        let fin_seq = send_until_fin(&mut tcb, &mut dev, IRS.wrapping_add(1));
+        let unacked = fin_seq.wrapping_sub(tcb.snd_una) as usize;
+        // the FIN is in flight, but it counts neither as data nor against the window
+        assert_eq!(tcb.bytes_in_flight() as usize, unacked + 1);
+        assert_eq!(tcb.data_in_flight(), unacked);
+
+        deliver(&mut tcb, &mut dev, &ack(fin_seq, &[]));
+        assert_eq!(tcb.bytes_in_flight(), 1);
+        assert_eq!(tcb.data_in_flight(), 0);
+        assert_eq!(
+            tcb.usable_wnd(),
+            (tcb.snd_wnd as usize).min(tcb.cwnd as usize)
+        );
+    }
+
+    #[test]
+    fn acceptability_of_each_length_and_window() {
+        let (mut tcb, _dev, _peer) = established();
+        let rcv_nxt = tcb.rcv_nxt;
+        let accepts = |tcb: &Tcb, seq: u32, len: usize| {
+            let seg = from_peer(seq, tcb.snd_una, false);
+            tcb.is_acceptable(&TcpHeaderSlice::from_slice(&seg).unwrap(), len)
+        };
+
+        // an empty segment has no last byte, that must not wrap to a huge length
+        assert!(accepts(&tcb, rcv_nxt, 0));
+        assert!(!accepts(&tcb, rcv_nxt.wrapping_sub(1), 0));
+        assert!(accepts(&tcb, rcv_nxt.wrapping_add(tcb.rcv_wnd - 1), 0));
+        assert!(!accepts(&tcb, rcv_nxt.wrapping_add(tcb.rcv_wnd), 0));
+        // text is acceptable when its first or its last byte is in the window
+        assert!(accepts(&tcb, rcv_nxt.wrapping_sub(10), 20));
+        assert!(!accepts(&tcb, rcv_nxt.wrapping_sub(10), 10));
+
+        // a closed window only takes an empty segment exactly at RCV.NXT
+        tcb.rcv_wnd = 0;
+        assert!(accepts(&tcb, rcv_nxt, 0));
+        assert!(!accepts(&tcb, rcv_nxt.wrapping_add(1), 0));
+        assert!(!accepts(&tcb, rcv_nxt, 1));
+    }
+
+    #[test]
+    fn window_shrunk_below_the_data_in_flight_sends_nothing() {
+        let (mut tcb, mut dev, peer) = established();
+        let _ = last_sent(&peer);
+        // the peer acknowledges nothing new but shrinks its window under what is in flight
+        let mut hdr = TcpHeader::new(40000, 8080, IRS.wrapping_add(1), 100);
+        hdr.ack = true;
+        hdr.acknowledgment_number = tcb.snd_una;
+        deliver(&mut tcb, &mut dev, &hdr.to_bytes());
+        assert_eq!(tcb.snd_wnd, 100);
+        assert!(tcb.data_in_flight() > 100);
+
+        let snd_nxt = tcb.snd_nxt;
+        assert_eq!(tcb.usable_wnd(), 0);
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(tcb.snd_nxt, snd_nxt);
+        assert!(peer.capture(Some(Duration::from_millis(1))).is_err());
+    }
+
+    #[test]
+    fn out_of_order_text_is_held_until_the_gap_fills() {
+        let (mut tcb, mut dev, peer) = established();
+        let rcv_nxt = tcb.rcv_nxt;
+
+        // the second half arrives first, it is held and the gap is acknowledged again
+        deliver_text(&mut tcb, &mut dev, rcv_nxt.wrapping_add(100), &[2; 100]);
+        assert_eq!(tcb.rcv_nxt, rcv_nxt);
+        assert!(tcb.rx_is_empty());
+        assert_eq!(last_sent(&peer).acknowledgment_number, rcv_nxt);
+
+        // the first half fills the gap, both become readable in order
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &[1; 100]);
+        assert_eq!(tcb.rcv_nxt, rcv_nxt.wrapping_add(200));
+        let mut buf = [0; 256];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 200);
+        assert_eq!(&buf[..100], &[1; 100]);
+        assert_eq!(&buf[100..200], &[2; 100]);
+    }
+
+    #[test]
+    fn text_overlapping_a_window_edge_is_trimmed() {
+        let (mut tcb, mut dev, _peer) = established();
+        let rcv_nxt = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &[1; 50]);
+
+        // a retransmission of those 50 bytes carrying 50 new ones, only the new ones are kept
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &[2; 100]);
+        assert_eq!(tcb.rcv_nxt, rcv_nxt.wrapping_add(100));
+
+        // text running past the right edge of the window is cut there
+        tcb.rcv_wnd = 30;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt.wrapping_add(100), &[3; 100]);
+        assert_eq!(tcb.rcv_nxt, rcv_nxt.wrapping_add(130));
+
+        let mut buf = [0; 256];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 130);
+        assert_eq!(&buf[..50], &[1; 50]);
+        assert_eq!(&buf[50..100], &[2; 50]);
+        assert_eq!(&buf[100..130], &[3; 30]);
+    }
+
+    #[test]
+    fn draining_a_full_receive_buffer_updates_the_window() {
+        let (mut tcb, mut dev, peer) = established();
+        // nothing left to send, only a window update can go out
+        ack_everything(&mut tcb, &mut dev);
+        let (capacity, rcv_nxt) = (tcb.rx_buffer.capacity(), tcb.rcv_nxt);
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &vec![1; capacity]);
+        assert_eq!(tcb.rcv_wnd, 0);
+        let _ = last_sent(&peer);
+
+        // the application reads everything, the peer learns about the room without asking
+        let mut buf = vec![0; capacity];
+        assert_eq!(tcb.read(&mut buf).unwrap(), capacity);
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(last_sent(&peer).window_size as usize, capacity);
+    }
+
+    #[test]
+    fn zero_window_is_probed_until_it_reopens() {
+        let (mut tcb, mut dev, peer) = established();
+        tcb.persist_interval = Duration::from_millis(10);
+        // the peer takes everything in flight and closes its window
+        let mut hdr = TcpHeader::new(40000, 8080, IRS.wrapping_add(1), 0);
+        hdr.ack = true;
+        hdr.acknowledgment_number = tcb.snd_nxt;
+        deliver(&mut tcb, &mut dev, &hdr.to_bytes());
+        let _ = last_sent(&peer);
+        assert_eq!(tcb.snd_wnd, 0);
+        assert!(!tcb.tx_is_empty());
+
+        // nothing goes out until the persist timer expires, then a single byte
+        tcb.on_tick(&mut dev).unwrap();
+        assert!(peer.capture(Some(Duration::from_millis(1))).is_err());
+        std::thread::sleep(Duration::from_millis(15));
+        tcb.on_tick(&mut dev).unwrap();
+        let pkt = peer.capture(Some(Duration::from_millis(1))).unwrap();
+        let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+        let Some(etherparse::TransportSlice::Tcp(probe)) = pkt.transport else {
+            panic!("expected a TCP segment");
+        };
+        assert_eq!(probe.sequence_number(), tcb.snd_una);
+        assert_eq!(probe.payload().len(), 1);
+
+        // the window reopens, the persist timer stops and the data flows again
+        let reopen = ack(tcb.snd_una, &[]);
+        deliver(&mut tcb, &mut dev, &reopen);
+        assert!(!tcb.timers.persist_running());
+        tcb.on_tick(&mut dev).unwrap();
+        assert!(seq::gt(tcb.snd_nxt, tcb.snd_una.wrapping_add(1)));
+    }
+
+    #[test]
+    fn rtt_samples_follow_rfc_6298() {
+        let (mut tcb, mut dev, _peer) = established();
+        tcb.rto_min = Duration::from_millis(1);
+        // the first sample comes from the ACK of the data in flight
+        std::thread::sleep(Duration::from_millis(20));
+        let seg = ack(tcb.snd_nxt, &[]);
+        deliver(&mut tcb, &mut dev, &seg);
+        let first = tcb.srtt.unwrap();
+        assert!(first >= Duration::from_millis(20));
+        assert_eq!(tcb.rttvar, first / 2);
+        assert_eq!(tcb.rto, first + tcb.rttvar * 4);
+
+        // later ones are smoothed, RTTVAR first since it takes the old SRTT
+        tcb.srtt = Some(Duration::from_millis(100));
+        tcb.rttvar = Duration::from_millis(50);
+        tcb.on_rtt_sample(Duration::from_millis(200));
+        assert_eq!(tcb.rttvar, Duration::from_micros(62_500));
+        assert_eq!(tcb.srtt, Some(Duration::from_micros(112_500)));
+        assert_eq!(tcb.rto, Duration::from_micros(362_500));
+    }
+
+    #[test]
+    fn ack_of_a_retransmission_takes_no_rtt_sample() {
+        let (mut tcb, mut dev, _peer) = established();
+        tcb.retransmit_una(&mut dev).unwrap();
+        let rto = tcb.rto;
+
+        // it is unknown which transmission this ACK is for
+        let seg = ack(tcb.snd_nxt, &[]);
+        deliver(&mut tcb, &mut dev, &seg);
+        assert_eq!(tcb.snd_una, tcb.snd_nxt);
+        assert_eq!(tcb.srtt, None);
+        assert_eq!(tcb.rto, rto);
+
+        // the next segment sent only once is timed again
+        tcb.on_tick(&mut dev).unwrap();
+        let seg = ack(tcb.snd_nxt, &[]);
+        deliver(&mut tcb, &mut dev, &seg);
+        assert!(tcb.srtt.is_some());
+    }
+
+    #[test]
+    fn rto_backoff_is_floored_and_capped() {
+        let (mut tcb, mut dev, _peer) = established();
+        tcb.rto_min = Duration::from_millis(10);
+        tcb.rto_max = Duration::from_millis(40);
+        tcb.rto = tcb.rto_min;
+        // a single segment in flight, timed with the shorter RTO
+        ack_everything(&mut tcb, &mut dev);
+        tcb.write(&[7; 100]).unwrap();
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(tcb.timers.rto_count(), 1);
+
+        // every timeout doubles the RTO, up to the cap
+        let start = Instant::now();
+        for expected in [20, 40, 40] {
+            let retransmits = tcb.retransmits;
+            while tcb.retransmits == retransmits {
+                std::thread::sleep(Duration::from_millis(1));
+                tcb.on_tick(&mut dev).unwrap();
+            }
+            assert_eq!(tcb.rto, Duration::from_millis(expected));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(10 + 20 + 40));
+
+        // a tiny sample doesn't take it below the floor
+        tcb.on_rtt_sample(Duration::from_micros(1));
+        assert_eq!(tcb.rto, tcb.rto_min);
+    }
+
+    #[test]
+    fn third_duplicate_ack_retransmits_right_away() {
+        let (mut tcb, mut dev, peer) = established();
+        let _ = last_sent(&peer);
+        let (snd_una, ssthresh) = (tcb.snd_una, tcb.ssthresh);
+        let dup = ack(snd_una, &[]);
+
+        // two duplicates may be reordering, nothing is resent
+        deliver(&mut tcb, &mut dev, &dup);
+        deliver(&mut tcb, &mut dev, &dup);
+        assert_eq!(tcb.segments_retransmitted, 0);
+
+        // the third one means loss, the first unacknowledged segment goes out again
+        deliver(&mut tcb, &mut dev, &dup);
+        assert_eq!(tcb.dup_acks, 3);
+        assert_eq!(tcb.segments_retransmitted, 1);
+        assert_eq!(last_sent(&peer).sequence_number, snd_una);
+        assert!(tcb.ssthresh < ssthresh);
+        assert_eq!(tcb.recover, Some(tcb.snd_nxt));
+    }
+
+    #[test]
+    fn ack_for_in_order_text_is_delayed() {
+        let (mut tcb, mut dev, peer) = established();
+        ack_everything(&mut tcb, &mut dev);
+        let _ = last_sent(&peer);
+        tcb.ack_delay = Duration::from_millis(10);
+        let rcv_nxt = tcb.rcv_nxt;
+
+        // a small segment is acknowledged once the delay is over
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &[1; 100]);
+        assert!(peer.capture(Some(Duration::from_millis(1))).is_err());
+        std::thread::sleep(Duration::from_millis(15));
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(
+            last_sent(&peer).acknowledgment_number,
+            rcv_nxt.wrapping_add(100)
+        );
+
+        // every second full-sized segment is acknowledged right away
+        let mut buf = vec![0; 4096];
+        tcb.read(&mut buf).unwrap();
+        let mss = tcb.mss as usize;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt.wrapping_add(100), &vec![2; mss]);
+        assert!(peer.capture(Some(Duration::from_millis(1))).is_err());
+        let rcv_nxt = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &vec![3; mss]);
+        assert_eq!(last_sent(&peer).acknowledgment_number, tcb.rcv_nxt);
+
+        // without a delay every segment is acknowledged at once
+        tcb.ack_delay = Duration::ZERO;
+        tcb.read(&mut buf).unwrap();
+        let rcv_nxt = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &[4; 10]);
+        assert_eq!(last_sent(&peer).acknowledgment_number, tcb.rcv_nxt);
+    }
+
+    #[test]
+    fn window_opens_only_in_large_steps() {
+        let (mut tcb, mut dev, peer) = established();
+        ack_everything(&mut tcb, &mut dev);
+        let (capacity, rcv_nxt) = (tcb.rx_buffer.capacity(), tcb.rcv_nxt);
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &vec![1; capacity]);
+        assert_eq!(tcb.rcv_wnd, 0);
+        let _ = last_sent(&peer);
+        let threshold = (tcb.local_mss() as usize).min(capacity / 2);
+
+        // reading less than the threshold leaves the window shut, nothing is announced
+        let mut buf = vec![0; capacity];
+        tcb.read(&mut buf[..threshold - 1]).unwrap();
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(tcb.rcv_wnd, 0);
+        assert!(peer.capture(Some(Duration::from_millis(1))).is_err());
+
+        // one more byte and all the free space is offered at once
+        tcb.read(&mut buf[..1]).unwrap();
+        assert_eq!(tcb.rcv_wnd as usize, threshold);
+        tcb.on_tick(&mut dev).unwrap();
+        assert_eq!(last_sent(&peer).window_size as usize, threshold);
+    }
+
+    #[test]
+    fn bytes_in_flight_across_the_wrap() {
+        // the data in flight straddles the end of the sequence space
+        let (mut tcb, mut dev, peer) = established_from(u32::MAX - 100);
+        assert!(tcb.snd_nxt < tcb.snd_una);
+        let mut sent = 0;
+        while let Ok(pkt) = peer.capture(Some(Duration::from_millis(1))) {
+            let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+            if let Some(etherparse::TransportSlice::Tcp(tcp)) = pkt.transport {
+                sent += tcp.payload().len();
+            }
+        }
+        assert!(sent > 100);
+        assert_eq!(tcb.bytes_in_flight() as usize, sent);
+
+        // an ACK beyond the wrap takes what it covers off
+        let seg = ack(tcb.snd_una.wrapping_add(200), &[]);
+        deliver(&mut tcb, &mut dev, &seg);
+        assert_eq!(tcb.bytes_in_flight() as usize, sent - 200);
+    }
+
+    #[test]
+    fn read_across_the_ring_buffer_wrap() {
+        let (mut tcb, mut dev, _peer) = established();
+        let capacity = tcb.rx_buffer.capacity();
+        let text: Vec<u8> = (0..capacity + 200).map(|i| i as u8).collect();
+        let rcv_nxt = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &text[..capacity - 100]);
+        let mut buf = vec![0; capacity];
+        assert_eq!(
+            tcb.read(&mut buf[..capacity - 200]).unwrap(),
+            capacity - 200
+        );
+        assert_eq!(buf[..capacity - 200], text[..capacity - 200]);
+
+        // the next text goes past the end of the storage and continues at its start
+        let rcv_nxt = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, rcv_nxt, &text[capacity - 100..]);
+        assert!(!tcb.rx_buffer.as_slices().1.is_empty());
+
+        // a single read takes both halves, in order
+        assert_eq!(tcb.read(&mut buf).unwrap(), 400);
+        assert_eq!(buf[..400], text[capacity - 200..]);
+        assert!(tcb.rx_buffer.is_empty());
+    }
+
+    #[test]
+    fn advertised_window_never_exceeds_the_free_space() {
+        let (mut tcb, mut dev, peer) = established();
+        ack_everything(&mut tcb, &mut dev);
+        tcb.ack_delay = Duration::ZERO;
+        let capacity = tcb.rx_buffer.capacity();
+        assert!(tcb.rcv_wnd as usize <= capacity);
+
+        // the peer fills whatever window it is offered while the application reads slowly
+        let mut buf = [0; 70];
+        for _ in 0..50 {
+            let rcv_nxt = tcb.rcv_nxt;
+            let len = (tcb.rcv_wnd as usize).min(150);
+            if len > 0 {
+                deliver_text(&mut tcb, &mut dev, rcv_nxt, &vec![1; len]);
+                assert!(last_sent(&peer).window_size as usize <= tcb.rx_buffer.free());
+            }
+            tcb.read(&mut buf).unwrap();
+            tcb.on_tick(&mut dev).unwrap();
+            while let Ok(pkt) = peer.capture(Some(Duration::from_millis(1))) {
+                let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+                let Some(etherparse::TransportSlice::Tcp(tcp)) = pkt.transport else {
+                    panic!("expected a TCP segment");
+                };
+                assert!(tcp.window_size() as usize <= tcb.rx_buffer.free());
+            }
+            assert!(tcb.rcv_wnd as usize <= tcb.rx_buffer.free());
+        }
+    }
+
+    #[test]
+    fn fin_with_text_is_taken_only_right_after_the_text() {
+        let (mut tcb, mut dev, _peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let start = tcb.rcv_nxt;
+        deliver_text(&mut tcb, &mut dev, start, b"hel");
+
+        // text partly received before, the FIN is still at SEG.SEQ + SEG.LEN
+        let seg = from_peer(start, tcb.snd_una, true);
+        let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+        tcb.on_segment(&mut dev, &seg, b"hello", &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(tcb.rcv_nxt, start.wrapping_add(6));
+        assert_eq!(tcb.state, State::CloseWait);
+        let mut buf = [0; 8];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // text cut at the right window edge leaves the FIN outside the window
+        let (mut tcb, mut dev, _peer) = established();
+        let start = tcb.rcv_nxt;
+        let room = tcb.rx_buffer.capacity() - 2;
+        deliver_text(&mut tcb, &mut dev, start, &vec![0; room]);
+        assert_eq!(tcb.rcv_wnd, 2);
+        let seq = tcb.rcv_nxt;
+        let seg = from_peer(seq, tcb.snd_una, true);
+        let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+        tcb.on_segment(&mut dev, &seg, b"abcd", &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(tcb.rcv_nxt, seq.wrapping_add(2));
+        assert_eq!(tcb.state, State::Estab);
+        assert!(!tcb.fin_received());
+    }
+
+    #[test]
+    fn fin_behind_a_hole_waits_for_the_gap_to_fill() {
+        let (mut tcb, mut dev, peer) = established();
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let start = tcb.rcv_nxt;
+
+        // "world" and the FIN arrive ahead of the missing "hello "
+        let seg = from_peer(start.wrapping_add(6), tcb.snd_una, true);
+        let seg = TcpHeaderSlice::from_slice(&seg).unwrap();
+        tcb.on_segment(&mut dev, &seg, b"world", &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(tcb.state, State::Estab);
+        assert!(!tcb.fin_received());
+        assert_eq!(tcb.rcv_nxt, start);
+        assert_eq!(last_sent(&peer).acknowledgment_number, start);
+
+        // the gap fills, the text and then the FIN are taken
+        deliver_text(&mut tcb, &mut dev, start, b"hello ");
+        assert_eq!(tcb.state, State::CloseWait);
+        assert!(tcb.fin_received());
+        assert_eq!(tcb.rcv_nxt, start.wrapping_add(12));
+        let mut buf = [0; 16];
+        assert_eq!(tcb.read(&mut buf).unwrap(), 11);
+        assert_eq!(&buf[..11], b"hello world");
+    }
 
-        let mut datagram = Vec::<u8>::with_capacity(builder.size(payload.len()));
-        match builder.write(&mut datagram, payload) {
-            Ok(_) => dev.send(datagram.as_slice()),
-            Err(_) => Err(std::io::Error::other("Packet serialization failed")),
+    #[test]
+    fn fast_path_takes_a_timestamped_ack() {
+        let (mut fast, mut fast_dev, _fast_peer) = established();
+        let (mut slow, mut slow_dev, _slow_peer) = established();
+        for tcb in [&mut fast, &mut slow] {
+            tcb.timestamps = true;
+            tcb.ts_recent = 100;
         }
+        let (read_cvar, write_cvar) = (Condvar::new(), Condvar::new());
+        let seg_ack = fast.snd_nxt;
+        let tsecr = fast.ts_now();
+
+        // PAWS still applies: an old TSval is dropped, not taken as an ACK
+        let old = ack(seg_ack, &timestamp(50, tsecr, 0));
+        let old = TcpHeaderSlice::from_slice(&old).unwrap();
+        fast.on_segment(&mut fast_dev, &old, &[], &read_cvar, &write_cvar)
+            .unwrap();
+        assert_eq!(fast.fast_path_acks, 0);
+        assert_ne!(fast.snd_una, seg_ack);
+
+        // the aligned option is predicted, extra padding sends it down the slow path
+        let aligned = ack(seg_ack, &timestamp(200, tsecr, 0));
+        let padded = ack(seg_ack, &timestamp(200, tsecr, 4));
+        let aligned = TcpHeaderSlice::from_slice(&aligned).unwrap();
+        let padded = TcpHeaderSlice::from_slice(&padded).unwrap();
+        fast.on_segment(&mut fast_dev, &aligned, &[], &read_cvar, &write_cvar)
+            .unwrap();
+        slow.on_segment(&mut slow_dev, &padded, &[], &read_cvar, &write_cvar)
+            .unwrap();
+
+        assert_eq!((fast.fast_path_acks, slow.fast_path_acks), (1, 0));
+        assert_eq!(fast.snd_una, seg_ack);
+        assert_eq!((fast.ts_recent, slow.ts_recent), (200, 200));
+        assert_eq!(observe(&fast), observe(&slow));
     }
 }
@@ -1,9 +1,14 @@
-use crate::{connections::ConnectionManager, socket::Socket};
+use crate::{
+    connections::ConnectionManager,
+    socket::Socket,
+    tcb::{Keepalive, SocketOptions, State, TcpStats},
+};
 
 use std::{
     io::{self},
-    net::SocketAddr,
+    net::{Shutdown, SocketAddr},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 pub struct TcpListener {
@@ -11,18 +16,88 @@ pub struct TcpListener {
 }
 
 impl TcpListener {
-    pub fn bind(addr: SocketAddr, mgr: Arc<ConnectionManager>) -> io::Result<TcpListener> {
-        let mut sock = Socket::new(addr, mgr.clone());
-        sock.bind(addr)?;
+    pub fn bind(addr: SocketAddr, mgr: Arc<ConnectionManager>) -> crate::Result<TcpListener> {
+        Self::bind_with_options(addr, mgr, SocketOptions::default())
+    }
+
+    /// Like `bind`, but the listener and the connections it accepts use `options`
+    pub fn bind_with_options(
+        addr: SocketAddr,
+        mgr: Arc<ConnectionManager>,
+        options: SocketOptions,
+    ) -> crate::Result<TcpListener> {
+        if options.rx_buffer == 0 || options.tx_buffer == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer capacities must not be zero",
+            )
+            .into());
+        }
+        let mut sock = Socket::new(addr, mgr);
+        sock.bind(addr, options)?;
         sock.listen();
         Ok(TcpListener { inner: sock })
     }
 
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking);
+        Ok(())
+    }
+
+    /// The bound address, with the port that was picked when binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr())
+    }
+
+    /// Window to advertise on the SYN-ACK of accepted connections
+    pub fn set_initial_rcv_wnd(&mut self, wnd: u16) {
+        self.inner.set_initial_rcv_wnd(wnd);
+    }
+
+    /// Time after which connections that haven't completed the handshake are reset
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.inner.set_handshake_timeout(timeout);
+    }
+
     pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
         let sock = self.inner.accept()?;
         let addr = sock.remote_addr();
         Ok((TcpStream { inner: sock }, addr))
     }
+
+    /// Returns an iterator that accepts connections forever, never returning `None`
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // frees the port for the next bind
+        self.inner.unbind();
+    }
+}
+
+/// Iterator over the connections accepted by a `TcpListener`, see `TcpListener::incoming`
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<io::Result<TcpStream>> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+impl<'a> IntoIterator for &'a TcpListener {
+    type Item = io::Result<TcpStream>;
+    type IntoIter = Incoming<'a>;
+
+    fn into_iter(self) -> Incoming<'a> {
+        self.incoming()
+    }
 }
 
 pub struct TcpStream {
@@ -30,25 +105,192 @@ pub struct TcpStream {
 }
 
 impl TcpStream {
-    pub fn connect(_addr: SocketAddr) -> io::Result<TcpStream> {
-        unimplemented!()
+    /// Connects from `local` to `remote`, blocking until the handshake is
+    /// complete. Port 0 in `local` picks an ephemeral port.
+    pub fn connect(
+        local: SocketAddr,
+        remote: SocketAddr,
+        mgr: Arc<ConnectionManager>,
+    ) -> crate::Result<TcpStream> {
+        Self::connect_with_options(local, remote, mgr, SocketOptions::default())
+    }
+
+    /// Like `connect`, with the buffer sizes and IP settings of `options`
+    pub fn connect_with_options(
+        local: SocketAddr,
+        remote: SocketAddr,
+        mgr: Arc<ConnectionManager>,
+        options: SocketOptions,
+    ) -> crate::Result<TcpStream> {
+        let inner = Socket::connect(local, remote, mgr, options)?;
+        Ok(TcpStream { inner })
+    }
+
+    /// Remote address of the connection
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        if !self.inner.is_connected() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        Ok(self.inner.remote_addr())
+    }
+
+    /// Local address of the connection
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        if !self.inner.is_connected() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        Ok(self.inner.local_addr())
+    }
+
+    /// Current state of the connection
+    pub fn state(&self) -> State {
+        self.inner.state()
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state() == State::Estab
+    }
+
+    /// Whether the three-way handshake is still in progress
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state(), State::SynSent | State::SynRcvd)
+    }
+
+    /// Whether the peer marked data that hasn't been read yet as urgent.
+    /// Urgent data is delivered in-band, along with the rest of the stream.
+    pub fn has_urgent_data(&self) -> bool {
+        self.inner.has_urgent_data()
+    }
+
+    /// Counters and congestion state of the connection, still available after it closed
+    pub fn stats(&self) -> TcpStats {
+        self.inner.stats()
+    }
+
+    /// Time at which the connection was established
+    pub fn established_at(&self) -> Option<Instant> {
+        self.inner.established_at()
+    }
+
+    /// How long the connection has been established for
+    pub fn duration(&self) -> Option<Duration> {
+        self.established_at().map(|at| at.elapsed())
+    }
+
+    /// In nonblocking mode `read` and `write` return `WouldBlock` instead of
+    /// waiting for data or buffer space
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking);
+        Ok(())
+    }
+
+    /// Sets the IPv4 TTL, or IPv6 hop limit, of the packets sent on this connection
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        let ttl = u8::try_from(ttl)
+            .ok()
+            .filter(|&ttl| ttl > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "TTL must be 1..=255"))?;
+        self.inner.set_ttl(ttl);
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        Ok(self.inner.ttl() as u32)
+    }
+
+    /// Sets the IPv4 TOS, or IPv6 traffic class, of the packets sent on this
+    /// connection. Only the DSCP bits are taken, the ECN bits are cleared.
+    pub fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.inner.set_tos(tos);
+        Ok(())
+    }
+
+    pub fn tos(&self) -> io::Result<u8> {
+        Ok(self.inner.tos())
+    }
+
+    /// Sets the send scheduling priority, higher priority connections are serviced
+    /// first. Fails with `NotConnected` once the connection is gone.
+    pub fn set_priority(&mut self, priority: u8) -> io::Result<()> {
+        self.inner.set_priority(priority)
+    }
+
+    /// Probes the peer after the connection has been idle for `keepalive.idle`,
+    /// every `keepalive.interval` after that, and closes it once `keepalive.probes`
+    /// probes went unanswered. `Keepalive::new` takes the default interval and
+    /// probe count. `None` disables keepalive.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.inner.set_keepalive(keepalive);
+        Ok(())
+    }
+
+    /// Resets the connection once neither the peer nor the application has
+    /// used it for `timeout`. `None`, the default, keeps idle connections.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_idle_timeout(timeout);
+        Ok(())
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.inner.read(buf)
     }
 
+    /// Like `read`, but leaves the data in the receive buffer
+    pub fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    /// Blocks until at least one byte of `buf` is queued for sending.
+    ///
+    /// Returns `Ok(0)` only once the connection is gone, and `BrokenPipe` if
+    /// it has already been shut down for writing.
     pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.inner.write(buf)
     }
 
-    pub fn shutdown(&mut self) {
-        self.inner.close();
+    /// Blocks until every written byte has been acknowledged by the peer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Limits how long `write` and `flush` block, they fail with `TimedOut`
+    /// afterwards. `None` blocks indefinitely.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(timeout);
+        Ok(())
+    }
+
+    /// Closes the connection right away by sending a RST instead of a FIN,
+    /// unsent and unread data is discarded
+    pub fn abort(&self) -> io::Result<()> {
+        self.inner.abort()
+    }
+
+    /// Shuts down the read half, the write half (sending a FIN once the
+    /// queued data is out) or both halves of the connection
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl io::Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl io::Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        TcpStream::flush(self)
     }
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
-        self.shutdown();
+        let _ = self.shutdown(Shutdown::Both);
     }
 }
@@ -3,13 +3,17 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::tcb::TcpFlags;
+use crate::{seq, tcb::TcpFlags};
 
 #[derive(Debug)]
 pub struct RTOEntry {
     expires_at: Instant,
     flags: TcpFlags,
     payload_len: usize,
+    /// Time the segment was (last) transmitted
+    sent_at: Instant,
+    /// The segment has been sent more than once, so its ACK is ambiguous
+    retransmitted: bool,
 }
 
 impl RTOEntry {
@@ -24,6 +28,14 @@ impl RTOEntry {
     pub fn payload_len(&self) -> usize {
         self.payload_len
     }
+
+    pub fn sent_at(&self) -> Instant {
+        self.sent_at
+    }
+
+    pub fn retransmitted(&self) -> bool {
+        self.retransmitted
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -48,6 +60,12 @@ impl PartialOrd for HeapEntry {
 pub struct TimerManager {
     heap: BinaryHeap<HeapEntry>,
     timers: HashMap<u32, RTOEntry>,
+    /// Expiry of the TIME-WAIT (2MSL) timer
+    time_wait: Option<Instant>,
+    /// Expiry of the zero-window persist timer
+    persist: Option<Instant>,
+    /// Expiry of the delayed-ACK timer
+    delayed_ack: Option<Instant>,
 }
 
 impl TimerManager {
@@ -55,17 +73,113 @@ impl TimerManager {
         Self {
             heap: BinaryHeap::new(),
             timers: HashMap::new(),
+            time_wait: None,
+            persist: None,
+            delayed_ack: None,
         }
     }
 
+    /// Cancels every retransmission timer
+    pub fn cancel_all_rto(&mut self) {
+        self.heap.clear();
+        self.timers.clear();
+    }
+
+    /// Starts, or restarts, the TIME-WAIT timer
+    pub fn start_time_wait(&mut self, timeout: Duration) {
+        self.time_wait = Some(Instant::now() + timeout);
+    }
+
+    pub fn time_wait_expired(&self) -> bool {
+        self.time_wait
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// Starts, or restarts, the persist timer
+    pub fn start_persist(&mut self, timeout: Duration) {
+        self.persist = Some(Instant::now() + timeout);
+    }
+
+    pub fn cancel_persist(&mut self) {
+        self.persist = None;
+    }
+
+    pub fn persist_running(&self) -> bool {
+        self.persist.is_some()
+    }
+
+    pub fn persist_expired(&self) -> bool {
+        self.persist
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// Starts the delayed-ACK timer, unless it is already running
+    pub fn start_delayed_ack(&mut self, timeout: Duration) {
+        self.delayed_ack.get_or_insert(Instant::now() + timeout);
+    }
+
+    pub fn cancel_delayed_ack(&mut self) {
+        self.delayed_ack = None;
+    }
+
+    pub fn delayed_ack_expired(&self) -> bool {
+        self.delayed_ack
+            .is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// Expiry of the TIME-WAIT timer, if it is running
+    pub fn time_wait_expiry(&self) -> Option<Instant> {
+        self.time_wait
+    }
+
+    /// Expiry of the persist timer, if it is running
+    pub fn persist_expiry(&self) -> Option<Instant> {
+        self.persist
+    }
+
+    /// Expiry of the delayed-ACK timer, if it is running
+    pub fn delayed_ack_expiry(&self) -> Option<Instant> {
+        self.delayed_ack
+    }
+
+    /// Number of segments with a running retransmission timer
+    pub fn rto_count(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// The earliest expiry among the running retransmission timers
+    pub fn next_rto_expiry(&self) -> Option<Instant> {
+        // the heap may hold stale entries of canceled timers, the map does not
+        self.timers.values().map(RTOEntry::expires_at).min()
+    }
+
     pub fn start_rto(&mut self, seq: u32, flags: TcpFlags, rto: Duration, payload_len: usize) {
-        let expires_at = Instant::now() + rto;
+        self.insert_rto(seq, flags, rto, payload_len, false);
+    }
+
+    /// Restarts the timer of a segment that has just been retransmitted
+    pub fn restart_rto(&mut self, seq: u32, flags: TcpFlags, rto: Duration, payload_len: usize) {
+        self.insert_rto(seq, flags, rto, payload_len, true);
+    }
+
+    fn insert_rto(
+        &mut self,
+        seq: u32,
+        flags: TcpFlags,
+        rto: Duration,
+        payload_len: usize,
+        retransmitted: bool,
+    ) {
+        let sent_at = Instant::now();
+        let expires_at = sent_at + rto;
         self.timers.insert(
             seq,
             RTOEntry {
                 expires_at,
                 flags,
                 payload_len,
+                sent_at,
+                retransmitted,
             },
         );
         self.heap.push(HeapEntry { expires_at, seq })
@@ -80,10 +194,16 @@ impl TimerManager {
         while let Some(top) = self.heap.peek() {
             if top.expires_at <= now {
                 let top = self.heap.pop().unwrap();
-                if let Some(entry) = self.timers.remove(&top.seq) {
+                // the timer may have been canceled or restarted since, skip
+                if self
+                    .timers
+                    .get(&top.seq)
+                    .is_some_and(|entry| entry.expires_at == top.expires_at)
+                {
+                    let entry = self.timers.remove(&top.seq).unwrap();
                     return Some((top.seq, entry));
                 } else {
-                    continue; // was canceled, skip
+                    continue;
                 }
             } else {
                 break;
@@ -92,14 +212,81 @@ impl TimerManager {
         None
     }
 
+    /// Cancels the timer of every segment `seg_ack` acknowledges entirely, passing
+    /// it to `f`. A partially acknowledged segment keeps running for its remainder.
     pub fn find_rto_by_ack<F: FnMut(u32, RTOEntry)>(&mut self, seg_ack: u32, mut f: F) {
         let keys: Vec<u32> = self.timers.keys().cloned().collect();
         for seq in keys {
-            if seq <= seg_ack {
-                if let Some(entry) = self.timers.remove(&seq) {
-                    f(seq, entry);
-                }
+            if !seq::lt(seq, seg_ack) {
+                continue;
+            }
+            let Some(mut entry) = self.timers.remove(&seq) else {
+                continue;
+            };
+            let end = seq
+                .wrapping_add(entry.payload_len as u32)
+                .wrapping_add(entry.flags.seq_len());
+            if seq::leq(end, seg_ack) {
+                f(seq, entry);
+            } else {
+                // only the acknowledged bytes are off the wire, restart at SEG.ACK
+                entry.payload_len -= seg_ack.wrapping_sub(seq) as usize;
+                self.heap.push(HeapEntry {
+                    expires_at: entry.expires_at,
+                    seq: seg_ack,
+                });
+                self.timers.insert(seg_ack, entry);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTO: Duration = Duration::from_secs(1);
+
+    // running timers as (seq, payload_len), in sequence order
+    fn running(timers: &TimerManager) -> Vec<(u32, usize)> {
+        let mut running: Vec<_> = timers
+            .timers
+            .iter()
+            .map(|(&seq, entry)| (seq, entry.payload_len))
+            .collect();
+        running.sort();
+        running
+    }
+
+    #[test]
+    fn ack_cancels_only_fully_acknowledged_segments() {
+        let mut timers = TimerManager::new();
+        for seq in [100, 200, 300] {
+            timers.start_rto(seq, TcpFlags::default(), RTO, 100);
+        }
+
+        // the first segment is covered, the second only up to 250
+        let mut acked = Vec::new();
+        timers.find_rto_by_ack(250, |seq, entry| acked.push((seq, entry.payload_len)));
+        assert_eq!(acked, [(100, 100)]);
+        assert_eq!(running(&timers), [(250, 50), (300, 100)]);
+
+        // an ACK of exactly the start of a segment leaves that segment running
+        acked.clear();
+        timers.find_rto_by_ack(300, |seq, entry| acked.push((seq, entry.payload_len)));
+        assert_eq!(acked, [(250, 50)]);
+        assert_eq!(running(&timers), [(300, 100)]);
+    }
+
+    #[test]
+    fn partially_acknowledged_segment_keeps_its_expiry() {
+        let mut timers = TimerManager::new();
+        timers.start_rto(u32::MAX - 49, TcpFlags::default(), RTO, 100);
+        let expires_at = timers.next_rto_expiry().unwrap();
+
+        // across the wrap, 50 of the 100 bytes are acknowledged
+        timers.find_rto_by_ack(0, |_, _| panic!("the segment is not fully acknowledged"));
+        assert_eq!(running(&timers), [(0, 50)]);
+        assert_eq!(timers.next_rto_expiry(), Some(expires_at));
+    }
+}
@@ -0,0 +1,1959 @@
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use mini_tcp::{
+    TcpError,
+    connections::{ConnectionManager, Tuple},
+    device::PacketDevice,
+    filter::{PacketFilter, Verdict},
+    loopback::{LoopbackDevice, LoopbackPeer},
+    packet_loop::packet_loop,
+    tcb::{Keepalive, SocketOptions, State},
+    tcp::{TcpListener, TcpStream},
+};
+
+/// Two stacks, each on its own loopback device, wired to each other
+struct Link {
+    client: Arc<ConnectionManager>,
+    server: Arc<ConnectionManager>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Link {
+    fn new() -> Link {
+        Self::with_devices(|_, _| {})
+    }
+
+    fn with_client(setup: impl FnOnce(&mut LoopbackDevice)) -> Link {
+        Self::with_devices(|client, _| setup(client))
+    }
+
+    // `setup` configures the client's and the server's device, e.g. their
+    // filters, before the stacks start
+    fn with_devices(setup: impl FnOnce(&mut LoopbackDevice, &mut LoopbackDevice)) -> Link {
+        let (mut client_dev, client_peer) = LoopbackDevice::pair().unwrap();
+        let (mut server_dev, server_peer) = LoopbackDevice::pair().unwrap();
+        setup(&mut client_dev, &mut server_dev);
+        let client_peer = Arc::new(client_peer);
+        let server_peer = Arc::new(server_peer);
+        let client = Arc::new(ConnectionManager::new());
+        let server = Arc::new(ConnectionManager::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = vec![
+            spawn_stack(client_dev, client.clone()),
+            spawn_stack(server_dev, server.clone()),
+            forward(client_peer.clone(), server_peer.clone(), stop.clone()),
+            forward(server_peer, client_peer, stop.clone()),
+        ];
+        Link {
+            client,
+            server,
+            stop,
+            threads,
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        // stop forwarding first, a stack that left its loop has closed its device
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(2..) {
+            thread.join().unwrap();
+        }
+        self.client.shutdown();
+        self.server.shutdown();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn spawn_stack(
+    mut dev: impl PacketDevice + Send + 'static,
+    mgr: Arc<ConnectionManager>,
+) -> JoinHandle<()> {
+    thread::spawn(move || packet_loop(&mut dev, mgr).unwrap())
+}
+
+// hand every packet one stack sends to the other one
+fn forward(
+    from: Arc<LoopbackPeer>,
+    to: Arc<LoopbackPeer>,
+    stop: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            match from.capture(Some(Duration::from_millis(10))) {
+                Ok(pkt) => to.inject(&pkt).unwrap(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("capture failed: {e}"),
+            }
+        }
+    })
+}
+
+const SERVER: &str = "10.0.0.1:8080";
+const CLIENT: &str = "10.0.0.2:0";
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn handshake_transfer_and_close() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        stream.write_all(&received).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        received.len()
+    });
+
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    assert!(client.is_established());
+    // more than the send buffer holds, so the transfer takes several windows
+    let data: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+    client.write_all(&data).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).unwrap();
+
+    assert_eq!(server.join().unwrap(), data.len());
+    assert_eq!(echoed, data);
+}
+
+/// What the filters of `full_sized_segments_fit_the_mtu` saw and do
+#[derive(Default)]
+struct SizeProbe {
+    /// Size of the largest packet either stack sent
+    largest: AtomicUsize,
+    /// Set once a data segment carried SACK blocks
+    sacked_data: AtomicBool,
+    /// Whether every 16th data segment is lost the first time it is sent
+    lossy: AtomicBool,
+}
+
+fn probe_filter(probe: Arc<SizeProbe>) -> PacketFilter {
+    let mut sent = HashSet::new();
+    Box::new(move |pkt| {
+        probe.largest.fetch_max(pkt.len(), Ordering::Relaxed);
+        let Some(seq) = data_seq(pkt) else {
+            return Verdict::Accept;
+        };
+        let sliced = etherparse::SlicedPacket::from_ip(pkt).unwrap();
+        if let Some(etherparse::TransportSlice::Tcp(tcp)) = sliced.transport
+            && tcp.options_iterator().any(|option| {
+                matches!(
+                    option,
+                    Ok(etherparse::TcpOptionElement::SelectiveAcknowledgement(..))
+                )
+            })
+        {
+            probe.sacked_data.store(true, Ordering::Relaxed);
+        }
+        if probe.lossy.load(Ordering::Relaxed) && sent.insert(seq) && sent.len() % 16 == 0 {
+            return Verdict::Drop;
+        }
+        Verdict::Accept
+    })
+}
+
+// send `data` while receiving whatever the peer sends, until it shuts down
+fn exchange(stream: &mut TcpStream, data: &[u8]) -> Vec<u8> {
+    stream.set_nonblocking(true).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let (mut written, mut received, mut eof) = (0, Vec::new(), false);
+    let mut buf = [0u8; 4096];
+    while written < data.len() || !eof {
+        assert!(Instant::now() < deadline, "exchange stalled");
+        let mut progress = false;
+        if written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(n) => (written, progress) = (written + n, true),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("write failed: {e}"),
+            }
+            if written == data.len() {
+                stream.shutdown(Shutdown::Write).unwrap();
+            }
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => eof = true,
+            Ok(n) => {
+                received.extend_from_slice(&buf[..n]);
+                progress = true;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {e}"),
+        }
+        if !progress {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    received
+}
+
+#[test]
+fn full_sized_segments_fit_the_mtu() {
+    // buffers larger than the MSS, so segments carry as much data as the MTU allows
+    let options = SocketOptions {
+        rx_buffer: 16 * 1024,
+        tx_buffer: 16 * 1024,
+        ..Default::default()
+    };
+    let probe = Arc::new(SizeProbe::default());
+    let link = Link::with_devices(|client, server| {
+        client.set_outbound_filter(Some(probe_filter(probe.clone())));
+        server.set_outbound_filter(Some(probe_filter(probe.clone())));
+    });
+    let listener =
+        TcpListener::bind_with_options(addr(SERVER), link.server.clone(), options).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        // then both ways at once, so each side sends while it holds SACK blocks
+        let (mut stream, _) = listener.accept().unwrap();
+        let echoed = exchange(&mut stream, &received);
+        (received, echoed)
+    });
+
+    let mut client =
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), link.client.clone(), options)
+            .unwrap();
+    // a dropped segment would stall the transfer on retransmission timeouts
+    client
+        .set_write_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let data: Vec<u8> = (0..256 * 1024u32).map(|i| (i % 251) as u8).collect();
+    client.write_all(&data).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    assert_eq!(client.stats().retransmits, 0);
+
+    // with losses, full segments make room for the SACK blocks or go without them
+    probe.lossy.store(true, Ordering::Relaxed);
+    let mut client =
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), link.client.clone(), options)
+            .unwrap();
+    let echoed = exchange(&mut client, &data);
+    let (received, sent_back) = server.join().unwrap();
+    assert_eq!(received, data);
+    assert_eq!(sent_back, data);
+    assert_eq!(echoed, data);
+    assert!(probe.sacked_data.load(Ordering::Relaxed));
+    assert!(probe.largest.load(Ordering::Relaxed) <= 1500);
+}
+
+#[test]
+fn server_writes_first() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let data: Vec<u8> = (0..4096u32).map(|i| i as u8).collect();
+    let greeting = data.clone();
+    let server = thread::spawn(move || {
+        // the handshake's last ACK is all the server heard of the client yet
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(&greeting).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+    });
+
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).unwrap();
+    server.join().unwrap();
+    assert_eq!(received, data);
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    let link = Link::new();
+    let Err(err) = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()) else {
+        panic!("connected to a port nobody listens on");
+    };
+    assert_eq!(
+        io::Error::from(err).kind(),
+        io::ErrorKind::ConnectionRefused
+    );
+}
+
+#[test]
+fn read_after_write_shutdown_sees_eof() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    // we close first, then the peer: our side ends up in TIME-WAIT
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(server.read(&mut buf).unwrap(), 0);
+    server.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+    assert_eq!(client.state(), State::TimeWait);
+}
+
+// a SYN from 10.0.0.2:40000 to `dest`, as the network would deliver it
+fn syn_to(dest: Ipv4Addr) -> Vec<u8> {
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 2], dest.octets(), 64)
+        .tcp(40000, 8080, 1000, 1024)
+        .syn();
+    let mut pkt = Vec::with_capacity(builder.size(0));
+    builder.write(&mut pkt, &[]).unwrap();
+    pkt
+}
+
+#[test]
+fn packets_for_foreign_addresses_are_dropped() {
+    let local = Ipv4Addr::new(10, 0, 0, 1);
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let dev = dev.with_local_addr(IpAddr::V4(local));
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr("0.0.0.0:8080"), mgr.clone()).unwrap();
+
+    // even a wildcard listener never sees a packet for an address we don't have
+    peer.inject(&syn_to(Ipv4Addr::new(10, 0, 0, 5))).unwrap();
+    let err = peer.capture(Some(Duration::from_millis(100))).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert!(
+        mgr.connections()
+            .pending()
+            .values()
+            .all(|queue| queue.is_empty())
+    );
+
+    peer.inject(&syn_to(local)).unwrap();
+    let reply = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    let reply = etherparse::SlicedPacket::from_ip(&reply).unwrap();
+    let Some(etherparse::TransportSlice::Tcp(tcp)) = reply.transport else {
+        panic!("expected a TCP reply");
+    };
+    assert!(tcp.syn() && tcp.ack());
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+/// Fails its first reads with `errors`, last one first, then reads from `dev`
+struct FlakyDevice {
+    dev: LoopbackDevice,
+    errors: Vec<io::Error>,
+}
+
+impl PacketDevice for FlakyDevice {
+    fn send(&mut self, pkt: &[u8]) -> io::Result<usize> {
+        self.dev.send(pkt)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.errors.pop() {
+            Some(e) => Err(e),
+            None => self.dev.recv(buf),
+        }
+    }
+
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.dev.as_fd()
+    }
+
+    fn mtu(&self) -> u16 {
+        self.dev.mtu()
+    }
+}
+
+#[test]
+fn transient_device_errors_keep_the_loop_serving() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let dev = FlakyDevice {
+        dev,
+        errors: vec![
+            io::Error::from_raw_os_error(libc::ENOBUFS),
+            io::Error::from(io::ErrorKind::Interrupted),
+        ],
+    };
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // the SYN waits out both failed reads, then the loop answers it
+    peer.inject(&syn_to(Ipv4Addr::new(10, 0, 0, 1))).unwrap();
+    let reply = capture_tcp(&peer);
+    assert!(reply.syn && reply.ack);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+// a segment from 10.0.0.2:`port` to SERVER, with the flags `set` puts on it
+fn segment(
+    port: u16,
+    seq: u32,
+    set: impl FnOnce(
+        etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+    ) -> etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+) -> Vec<u8> {
+    segment_with_window(port, seq, 1024, set)
+}
+
+// like `segment`, advertising `window` instead of 1024 bytes
+fn segment_with_window(
+    port: u16,
+    seq: u32,
+    window: u16,
+    set: impl FnOnce(
+        etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+    ) -> etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+) -> Vec<u8> {
+    let builder = set(
+        etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+            .tcp(port, 8080, seq, window),
+    );
+    let mut pkt = Vec::with_capacity(builder.size(0));
+    builder.write(&mut pkt, &[]).unwrap();
+    pkt
+}
+
+// the TCP header of the next packet the stack sends
+fn capture_tcp(peer: &LoopbackPeer) -> etherparse::TcpHeader {
+    let pkt = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+    let Some(etherparse::TransportSlice::Tcp(tcp)) = pkt.transport else {
+        panic!("expected a TCP segment");
+    };
+    tcp.to_header()
+}
+
+#[test]
+fn reset_connections_are_never_accepted_or_read() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let mut listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    // reset while half-open: the connection is dropped before anyone accepts it
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+    peer.inject(&segment(40000, 1001, |b| b.rst())).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while mgr
+        .connections()
+        .pending()
+        .values()
+        .any(|queue| !queue.is_empty())
+    {
+        assert!(Instant::now() < deadline, "reset half-open connection kept");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let Err(err) = listener.accept() else {
+        panic!("accepted a reset connection");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // reset once accepted: a blocked read fails instead of hanging
+    peer.inject(&segment(40001, 2000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    peer.inject(&segment(40001, 2001, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    listener.set_nonblocking(false).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    let reader = thread::spawn(move || stream.read(&mut [0u8; 16]).map_err(|e| e.kind()));
+    thread::sleep(Duration::from_millis(50));
+    peer.inject(&segment(40001, 2001, |b| b.rst())).unwrap();
+    assert_eq!(reader.join().unwrap(), Err(io::ErrorKind::ConnectionReset));
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+// sequence number of a TCP segment that carries data, as the stack sent it
+fn data_seq(pkt: &[u8]) -> Option<u32> {
+    let pkt = etherparse::SlicedPacket::from_ip(pkt).ok()?;
+    match pkt.transport? {
+        etherparse::TransportSlice::Tcp(tcp) if !tcp.payload().is_empty() => {
+            Some(tcp.sequence_number())
+        }
+        _ => None,
+    }
+}
+
+// send `data` from the client to the server's accepted connection, returning
+// what arrived and the client's number of retransmissions
+fn transfer(link: &Link, data: &[u8]) -> (Vec<u8>, u64) {
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    client
+        .set_write_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    client.write_all(data).unwrap();
+    client.flush().unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    (server.join().unwrap(), client.stats().retransmits)
+}
+
+#[test]
+fn transfer_recovers_from_dropped_segments() {
+    // every other data segment is lost the first time it is sent
+    let link = Link::with_client(|dev| {
+        let mut sent = HashSet::new();
+        dev.set_outbound_filter(Some(Box::new(move |pkt| match data_seq(pkt) {
+            Some(seq) if sent.insert(seq) && sent.len() % 2 == 0 => Verdict::Drop,
+            _ => Verdict::Accept,
+        })));
+    });
+    let data: Vec<u8> = (0..32 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let (received, retransmits) = transfer(&link, &data);
+    assert_eq!(received, data);
+    assert!(retransmits > 0);
+}
+
+#[test]
+fn delayed_packets_are_delivered() {
+    // everything the client sends, the SYN included, is held back a little
+    let link = Link::with_client(|dev| {
+        dev.set_outbound_filter(Some(Box::new(|_| {
+            Verdict::Delay(Duration::from_millis(20))
+        })));
+    });
+    let data: Vec<u8> = (0..8 * 1024u32).map(|i| i as u8).collect();
+
+    let start = Instant::now();
+    let (received, _) = transfer(&link, &data);
+    assert_eq!(received, data);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+// hold back bare SYNs, so the ones of two connects cross on the wire
+fn delay_syn(pkt: &[u8]) -> Verdict {
+    let pkt = etherparse::SlicedPacket::from_ip(pkt).unwrap();
+    match pkt.transport {
+        Some(etherparse::TransportSlice::Tcp(tcp)) if tcp.syn() && !tcp.ack() => {
+            Verdict::Delay(Duration::from_millis(50))
+        }
+        _ => Verdict::Accept,
+    }
+}
+
+#[test]
+fn simultaneous_open() {
+    let link = Link::with_devices(|client, server| {
+        client.set_outbound_filter(Some(Box::new(delay_syn)));
+        server.set_outbound_filter(Some(Box::new(delay_syn)));
+    });
+    let (a, b) = (addr("10.0.0.2:5000"), addr("10.0.0.1:6000"));
+
+    // no listener on either side, each connect() meets the other's SYN
+    let mgr = link.server.clone();
+    let other = thread::spawn(move || TcpStream::connect(b, a, mgr).unwrap());
+    let mut ours = TcpStream::connect(a, b, link.client.clone()).unwrap();
+    let mut theirs = other.join().unwrap();
+    assert_eq!(ours.state(), State::Estab);
+    assert_eq!(theirs.state(), State::Estab);
+
+    let mut buf = [0u8; 4];
+    ours.write_all(b"ping").unwrap();
+    theirs.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    theirs.write_all(b"pong").unwrap();
+    ours.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[test]
+fn nonblocking_accept() {
+    let link = Link::new();
+    let mut listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let Err(err) = listener.accept() else {
+        panic!("accepted a connection nobody opened");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    let client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (_stream, peer) = loop {
+        match listener.accept() {
+            Ok(accepted) => break accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                assert!(
+                    Instant::now() < deadline,
+                    "connection never became acceptable"
+                );
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => panic!("accept failed: {e}"),
+        }
+    };
+    assert_eq!(peer, client.local_addr().unwrap());
+}
+
+#[test]
+fn unanswered_retransmissions_time_out() {
+    let lost = Arc::new(AtomicBool::new(false));
+    let link = Link::with_client(|dev| {
+        let lost = lost.clone();
+        dev.set_outbound_filter(Some(Box::new(move |_| {
+            if lost.load(Ordering::Relaxed) {
+                Verdict::Drop
+            } else {
+                Verdict::Accept
+            }
+        })));
+    });
+    let options = SocketOptions {
+        rto_min: Duration::from_millis(10),
+        rto_max: Duration::from_millis(40),
+        max_retransmits: 3,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client =
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), link.client.clone(), options)
+            .unwrap();
+    let _server = listener.accept().unwrap();
+
+    // from now on nothing the client sends arrives, so nothing it sends is acknowledged
+    lost.store(true, Ordering::Relaxed);
+    client.write_all(b"into the void").unwrap();
+    let start = Instant::now();
+    let err = client.flush().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    // 3 retransmissions 10, 20 and 40ms apart, a default RTO would take seconds
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn shutdown_aborts_connections_and_blocked_calls() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let idle = TcpListener::bind(addr("10.0.0.1:8081"), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    let reader = thread::spawn(move || server.read(&mut [0u8; 16]).unwrap_err().kind());
+    let acceptor = thread::spawn(move || match idle.accept() {
+        Ok(_) => panic!("accepted a connection nobody opened"),
+        Err(e) => e.kind(),
+    });
+    // let both block first
+    thread::sleep(Duration::from_millis(20));
+
+    link.server.shutdown();
+    assert_eq!(reader.join().unwrap(), io::ErrorKind::ConnectionAborted);
+    assert_eq!(acceptor.join().unwrap(), io::ErrorKind::ConnectionAborted);
+    // the peer learns about it from the reset
+    let err = client.read(&mut [0u8; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    // and a stopped stack takes no new sockets
+    assert!(TcpListener::bind(addr("10.0.0.1:8082"), link.server.clone()).is_err());
+}
+
+#[test]
+fn syn_beyond_time_wait_reopens_the_tuple() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    let iss = syn_ack.sequence_number;
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(1))))
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    // the server closes first, then the peer: the server ends up in TIME-WAIT
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(capture_tcp(&peer).fin);
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(2)).fin()))
+        .unwrap();
+    assert!(capture_tcp(&peer).ack);
+    assert_eq!(stream.state(), State::TimeWait);
+
+    // a SYN within the old connection is only acknowledged
+    peer.inject(&segment(40000, 1001, |b| b.syn())).unwrap();
+    let reply = capture_tcp(&peer);
+    assert!(reply.ack && !reply.syn);
+    assert_eq!(stream.state(), State::TimeWait);
+
+    // one beyond its end starts a new connection on the same tuple
+    peer.inject(&segment(40000, 50_000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+    assert_eq!(syn_ack.acknowledgment_number, 50_001);
+    peer.inject(&segment(40000, 50_001, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    let (reopened, remote) = listener.accept().unwrap();
+    assert_eq!(remote, addr("10.0.0.2:40000"));
+    assert_eq!(reopened.state(), State::Estab);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn accept_blocks_again_once_nonblocking_is_off() {
+    let link = Link::new();
+    let mut listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    assert_eq!(
+        listener.accept().err().map(|e| e.kind()),
+        Some(io::ErrorKind::WouldBlock)
+    );
+
+    // back in blocking mode accept waits for the connection opened meanwhile
+    listener.set_nonblocking(false).unwrap();
+    let mgr = link.client.clone();
+    let client = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        TcpStream::connect(addr(CLIENT), addr(SERVER), mgr).unwrap()
+    });
+    let (stream, peer) = listener.accept().unwrap();
+    let client = client.join().unwrap();
+    assert_eq!(peer, client.local_addr().unwrap());
+    assert_eq!(stream.state(), State::Estab);
+}
+
+#[test]
+fn establishment_time_and_duration() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let before = Instant::now();
+    let client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let after = Instant::now();
+    let (server, _) = listener.accept().unwrap();
+
+    let established = client.established_at().unwrap();
+    assert!(before <= established && established <= after);
+    assert!(server.established_at().is_some());
+    let duration = client.duration().unwrap();
+    thread::sleep(Duration::from_millis(10));
+    assert!(client.duration().unwrap() >= duration + Duration::from_millis(10));
+}
+
+#[test]
+fn syn_ack_advertises_the_initial_window() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let mut listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // by default the whole receive buffer is offered
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert_eq!(
+        syn_ack.window_size as usize,
+        SocketOptions::default().rx_buffer.min(u16::MAX as usize)
+    );
+
+    // the override is independent of the buffer size
+    listener.set_initial_rcv_wnd(1000);
+    peer.inject(&segment(40001, 2000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+    assert_eq!(syn_ack.window_size, 1000);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn unanswered_syn_is_retransmitted_then_connect_times_out() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        rto_min: Duration::from_millis(10),
+        max_syn_retries: 2,
+        ..Default::default()
+    };
+    let connecting = mgr.clone();
+    let client = thread::spawn(move || {
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), connecting, options)
+            .err()
+            .map(|e| io::Error::from(e).kind())
+    });
+
+    // nobody answers: the same SYN goes out once, then once per retry
+    let syn = capture_tcp(&peer);
+    assert!(syn.syn && !syn.ack);
+    for _ in 0..2 {
+        let again = capture_tcp(&peer);
+        assert!(again.syn);
+        assert_eq!(again.sequence_number, syn.sequence_number);
+    }
+    assert_eq!(client.join().unwrap(), Some(io::ErrorKind::TimedOut));
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn unanswered_syn_ack_is_retransmitted() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        rto_min: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+    // the handshake's last ACK got lost, the SYN-ACK is sent again
+    let again = capture_tcp(&peer);
+    assert!(again.syn && again.ack);
+    assert_eq!(again.sequence_number, syn_ack.sequence_number);
+    assert_eq!(again.acknowledgment_number, 1001);
+
+    // and the handshake still completes
+    peer.inject(&segment(40000, 1001, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    assert_eq!(stream.state(), State::Estab);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn time_wait_ends_after_twice_the_msl() {
+    let link = Link::new();
+    let options = SocketOptions {
+        msl: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client =
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), link.client.clone(), options)
+            .unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut buf = [0u8; 16];
+    assert_eq!(server.read(&mut buf).unwrap(), 0);
+    server.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
+    let entered = Instant::now();
+    assert_eq!(client.state(), State::TimeWait);
+
+    // 2MSL later the TCB is released
+    while !link.client.connections().established().is_empty() {
+        assert!(
+            entered.elapsed() < Duration::from_secs(1),
+            "TIME-WAIT never ended"
+        );
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(entered.elapsed() >= Duration::from_millis(35));
+}
+
+#[test]
+fn send_window_starts_at_the_handshake_window() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    // room for more than the peer's window, so only the window holds data back
+    let options = SocketOptions {
+        tx_buffer: 8192,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    // the peer offers a 1024 byte window, without window scaling
+    let (mut stream, _) = accept_from(&peer, &listener);
+    stream.write_all(&[1; 4000]).unwrap();
+
+    // nothing is acknowledged, so no more than the window goes out
+    assert_eq!(first_flight(&peer).iter().sum::<usize>(), 1024);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+// complete a handshake from 10.0.0.2:40000, ISN 1000, returns the accepted
+// stream and the stack's ISN
+fn accept_from(peer: &LoopbackPeer, listener: &TcpListener) -> (TcpStream, u32) {
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let iss = capture_tcp(peer).sequence_number;
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(1))))
+        .unwrap();
+    (listener.accept().unwrap().0, iss)
+}
+
+// payload lengths of the segments the stack sends before its first
+// retransmission timeout could fire
+fn first_flight(peer: &LoopbackPeer) -> Vec<usize> {
+    let mut sent = Vec::new();
+    let deadline = Instant::now() + Duration::from_millis(150);
+    while let Some(left) = deadline.checked_duration_since(Instant::now())
+        && let Ok(pkt) = peer.capture(Some(left))
+    {
+        let pkt = etherparse::SlicedPacket::from_ip(&pkt).unwrap();
+        if let Some(etherparse::TransportSlice::Tcp(tcp)) = pkt.transport {
+            sent.push(tcp.payload().len());
+        }
+    }
+    sent
+}
+
+// inject `pkt` and check the stack sends nothing back
+fn assert_dropped(peer: &LoopbackPeer, pkt: &[u8]) {
+    peer.inject(pkt).unwrap();
+    let reply = peer.capture(Some(Duration::from_millis(100)));
+    assert_eq!(reply.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn segment_with_a_bad_checksum_is_dropped() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // flip a bit of the TCP checksum, right after the 20 byte IPv4 header
+    let syn = segment(40000, 1000, |b| b.syn());
+    let mut corrupt = syn.clone();
+    corrupt[20 + 16] ^= 1;
+    assert_dropped(&peer, &corrupt);
+    assert_eq!(mgr.checksum_errors(), 1);
+
+    // the intact SYN is answered
+    peer.inject(&syn).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn packet_with_a_bad_ip_header_checksum_is_dropped() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // the IPv4 header checksum sits at offset 10
+    let syn = segment(40000, 1000, |b| b.syn());
+    let mut corrupt = syn.clone();
+    corrupt[10] ^= 1;
+    assert_dropped(&peer, &corrupt);
+
+    peer.inject(&syn).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn data_offset_past_the_packet_end_is_dropped() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // claim a 60 byte TCP header where only 20 bytes follow the IPv4 header
+    let syn = segment(40000, 1000, |b| b.syn());
+    let mut truncated = syn.clone();
+    truncated[20 + 12] = 15 << 4;
+    assert_dropped(&peer, &truncated);
+
+    // the loop survived it
+    peer.inject(&syn).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn blocked_writer_wakes_once_the_peer_reads() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    // far more than both buffers hold, the writer blocks while nobody reads
+    let writer = thread::spawn(move || {
+        client
+            .set_write_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(&[9; 64 * 1024]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!writer.is_finished());
+
+    // reading frees the space the writer waits for
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).unwrap();
+    writer.join().unwrap();
+    assert_eq!(received.len(), 64 * 1024);
+}
+
+#[test]
+fn write_queues_some_bytes_or_fails_once_shut_down() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+
+    // a write larger than the send buffer queues part of it, never nothing
+    let n = client.write(&[3; 64 * 1024]).unwrap();
+    assert!(n > 0 && n < 64 * 1024);
+
+    // once the write half is shut down writing is a broken pipe
+    client.shutdown(Shutdown::Write).unwrap();
+    let err = client.write(&[3]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).unwrap();
+    assert_eq!(received.len(), n);
+}
+
+#[test]
+fn segments_respect_the_peer_mss() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+
+    // the peer takes segments of at most 500 bytes
+    peer.inject(&segment(40000, 1000, |b| {
+        b.syn()
+            .options(&[etherparse::TcpOptionElement::MaximumSegmentSize(500)])
+            .unwrap()
+    }))
+    .unwrap();
+    // and learns ours from the SYN-ACK
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.options_iterator().any(|option| matches!(
+        option,
+        Ok(etherparse::TcpOptionElement::MaximumSegmentSize(_))
+    )));
+    peer.inject(&segment(40000, 1001, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.write_all(&[1; 1000]).unwrap();
+
+    assert_eq!(first_flight(&peer).into_iter().max(), Some(500));
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn window_scale_shifts_the_peer_window() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        tx_buffer: 8192,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    // the peer scales its windows by 16
+    peer.inject(&segment(40000, 1000, |b| {
+        b.syn()
+            .options(&[etherparse::TcpOptionElement::WindowScale(4)])
+            .unwrap()
+    }))
+    .unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(
+        syn_ack
+            .options_iterator()
+            .any(|option| matches!(option, Ok(etherparse::TcpOptionElement::WindowScale(_))))
+    );
+    // 64 in the header is a 1024 byte window
+    peer.inject(&segment_with_window(40000, 1001, 64, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.write_all(&[1; 4000]).unwrap();
+
+    assert_eq!(first_flight(&peer).iter().sum::<usize>(), 1024);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn keepalive_closes_a_connection_to_a_vanished_peer() {
+    // once `cut` is set nothing reaches the client anymore
+    let cut = Arc::new(AtomicBool::new(false));
+    let link = Link::with_client(|dev| {
+        let cut = cut.clone();
+        dev.set_inbound_filter(Some(Box::new(move |_| {
+            if cut.load(Ordering::Relaxed) {
+                Verdict::Drop
+            } else {
+                Verdict::Accept
+            }
+        })));
+    });
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (_server, _) = listener.accept().unwrap();
+    client
+        .set_keepalive(Some(Keepalive {
+            idle: Duration::from_millis(100),
+            interval: Duration::from_millis(50),
+            probes: 3,
+        }))
+        .unwrap();
+
+    // an idle connection whose peer answers the probes stays up
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.state(), State::Estab);
+
+    // after the idle time and three unanswered probes it is closed
+    cut.store(true, Ordering::Relaxed);
+    let err = client.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn each_listener_accepts_only_its_own_connections() {
+    let link = Link::new();
+    let first = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let second = TcpListener::bind(addr("10.0.0.1:8081"), link.server.clone()).unwrap();
+
+    // the connection to the second listener is queued first
+    let to_second =
+        TcpStream::connect(addr(CLIENT), addr("10.0.0.1:8081"), link.client.clone()).unwrap();
+    let to_first = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+
+    let (stream, remote) = first.accept().unwrap();
+    assert_eq!(stream.local_addr().unwrap(), addr(SERVER));
+    assert_eq!(remote, to_first.local_addr().unwrap());
+    let (stream, remote) = second.accept().unwrap();
+    assert_eq!(stream.local_addr().unwrap(), addr("10.0.0.1:8081"));
+    assert_eq!(remote, to_second.local_addr().unwrap());
+}
+
+#[test]
+fn same_remote_endpoint_on_two_local_ports() {
+    let link = Link::new();
+    let first = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let second = TcpListener::bind(addr("10.0.0.1:8081"), link.server.clone()).unwrap();
+
+    // both handshakes come from 10.0.0.2:40000 and run at the same time
+    let connects: Vec<_> = [SERVER, "10.0.0.1:8081"]
+        .into_iter()
+        .map(|server| {
+            let client = link.client.clone();
+            thread::spawn(move || {
+                TcpStream::connect(addr("10.0.0.2:40000"), addr(server), client).unwrap()
+            })
+        })
+        .collect();
+    let mut clients: Vec<_> = connects.into_iter().map(|t| t.join().unwrap()).collect();
+
+    // each listener gets the connection to its own port, with its own data
+    for (listener, client) in [first, second].iter().zip(&mut clients) {
+        let (mut stream, remote) = listener.accept().unwrap();
+        assert_eq!(remote, addr("10.0.0.2:40000"));
+        assert_eq!(stream.local_addr().unwrap(), client.peer_addr().unwrap());
+        let port = stream.local_addr().unwrap().port();
+        client.write_all(&port.to_be_bytes()).unwrap();
+        let mut received = [0; 2];
+        stream.read_exact(&mut received).unwrap();
+        assert_eq!(u16::from_be_bytes(received), port);
+    }
+}
+
+#[test]
+fn syns_beyond_the_backlog_are_dropped() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        backlog: 2,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    // two half-open connections fill the backlog
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    peer.inject(&segment(40001, 1000, |b| b.syn())).unwrap();
+    assert!(capture_tcp(&peer).syn);
+    assert_dropped(&peer, &segment(40002, 1000, |b| b.syn()));
+
+    // completing a handshake makes room for the next one
+    peer.inject(&segment(40000, 1001, |b| {
+        b.ack(syn_ack.sequence_number.wrapping_add(1))
+    }))
+    .unwrap();
+    let (_stream, remote) = listener.accept().unwrap();
+    assert_eq!(remote, addr("10.0.0.2:40000"));
+    peer.inject(&segment(40003, 1000, |b| b.syn())).unwrap();
+    assert!(capture_tcp(&peer).syn);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn half_open_connection_is_reaped_after_the_handshake_timeout() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        backlog: 1,
+        ..Default::default()
+    };
+    let mut listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+    listener.set_handshake_timeout(Duration::from_millis(50));
+
+    // the handshake is never completed, the connection is reset
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    assert!(capture_tcp(&peer).syn);
+    let start = Instant::now();
+    let rst = capture_tcp(&peer);
+    assert!(rst.rst);
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    // and no longer takes up the backlog
+    peer.inject(&segment(40001, 1000, |b| b.syn())).unwrap();
+    assert!(capture_tcp(&peer).syn);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn nonblocking_read_and_write() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client.set_nonblocking(true).unwrap();
+
+    // nothing to read yet
+    let err = client.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    server.write_all(b"ping").unwrap();
+    let mut buf = [0; 16];
+    let n = loop {
+        match client.read(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+            n => break n.unwrap(),
+        }
+    };
+    assert_eq!(&buf[..n], b"ping");
+
+    // fill the send buffer and the peer's window until writing would block
+    let mut written = 0;
+    loop {
+        match client.write(&[5; 4096]) {
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => panic!("write failed: {e}"),
+        }
+    }
+    assert!(written > 0);
+
+    // once the peer reads, there is room again
+    let mut received = vec![0; written];
+    server.read_exact(&mut received).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while let Err(e) = client.write(&[5]) {
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert!(Instant::now() < deadline, "no room freed");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn peek_leaves_the_data_for_read() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    server.write_all(b"hello").unwrap();
+    server.shutdown(Shutdown::Write).unwrap();
+
+    // peek blocks until there is data, it may arrive in pieces
+    let mut first = [0; 5];
+    while client.peek(&mut first).unwrap() < first.len() {}
+    let mut second = [0; 5];
+    assert_eq!(client.peek(&mut second).unwrap(), 5);
+    assert_eq!(&first, b"hello");
+    assert_eq!(&second, b"hello");
+
+    // read takes the peeked bytes, exactly once
+    let mut read = Vec::new();
+    client.read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"hello");
+}
+
+#[test]
+fn a_locked_connection_does_not_stall_the_others() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let idle = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let _idle_server = listener.accept().unwrap();
+    let mut busy = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut busy_server, _) = listener.accept().unwrap();
+
+    // hold the lock of the idle connection, but not the connections map
+    let tuple = Tuple::new(idle.local_addr().unwrap(), addr(SERVER));
+    let conn = link.client.connections().established()[&tuple].clone();
+    let _locked = conn.tcb();
+
+    // the other connection keeps moving data both ways
+    let echo = thread::spawn(move || {
+        let mut buf = vec![0; 8192];
+        busy_server.read_exact(&mut buf).unwrap();
+        busy_server.write_all(&buf).unwrap();
+    });
+    busy.write_all(&[6; 8192]).unwrap();
+    let mut echoed = vec![0; 8192];
+    busy.read_exact(&mut echoed).unwrap();
+    echo.join().unwrap();
+    assert_eq!(echoed, vec![6; 8192]);
+}
+
+#[test]
+fn retransmission_fires_on_schedule() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        rto_min: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+    let (mut stream, _) = accept_from(&peer, &listener);
+
+    // the segment is never acknowledged, it goes out again one RTO later
+    stream.write_all(b"lost").unwrap();
+    let first = capture_tcp(&peer);
+    let sent_at = Instant::now();
+    let again = capture_tcp(&peer);
+    let waited = sent_at.elapsed();
+    assert_eq!(again.sequence_number, first.sequence_number);
+    assert!(
+        waited >= Duration::from_millis(95),
+        "fired early: {waited:?}"
+    );
+    assert!(
+        waited < Duration::from_millis(150),
+        "fired late: {waited:?}"
+    );
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn port_zero_takes_distinct_ephemeral_ports() {
+    let link = Link::new();
+    let ephemeral = 49152..=65535;
+    let first = TcpListener::bind(addr("10.0.0.1:0"), link.server.clone()).unwrap();
+    let second = TcpListener::bind(addr("10.0.0.1:0"), link.server.clone()).unwrap();
+    let (first, second) = (
+        first.local_addr().unwrap().port(),
+        second.local_addr().unwrap().port(),
+    );
+    assert_ne!(first, second);
+    assert!(ephemeral.contains(&first) && ephemeral.contains(&second));
+
+    // an active open picks its local port the same way
+    let server = SocketAddr::new(addr(SERVER).ip(), first);
+    let client = TcpStream::connect(addr(CLIENT), server, link.client.clone()).unwrap();
+    assert!(ephemeral.contains(&client.local_addr().unwrap().port()));
+}
+
+#[test]
+fn wildcard_listener_adopts_the_destination_address() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr("0.0.0.0:8080"), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    // the accepted connection answers from the address the SYN was sent to
+    assert_eq!(stream.local_addr().unwrap(), addr(SERVER));
+    client.write_all(b"hi").unwrap();
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+}
+
+#[test]
+fn stats_follow_the_traffic() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    let before = client.stats();
+    assert_eq!((before.bytes_sent, before.bytes_received), (0, 0));
+
+    let echo = thread::spawn(move || {
+        let mut buf = vec![0; 5000];
+        server.read_exact(&mut buf).unwrap();
+        server.write_all(&[2; 300]).unwrap();
+        server
+    });
+    client.write_all(&[1; 5000]).unwrap();
+    client.read_exact(&mut [0; 300]).unwrap();
+    let server = echo.join().unwrap();
+
+    let stats = client.stats();
+    assert_eq!(stats.bytes_sent, 5000);
+    assert_eq!(stats.bytes_received, 300);
+    assert!(stats.srtt.is_some());
+    assert!(stats.cwnd > 0 && stats.snd_wnd > 0 && stats.rcv_wnd > 0);
+    assert_eq!(stats.retransmits, 0);
+    assert_eq!(server.stats().bytes_received, 5000);
+}
+
+#[test]
+fn state_follows_the_close_sequence() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    assert!(client.is_established() && !client.is_connecting());
+    assert_eq!(server.state(), State::Estab);
+
+    // the client closes first, the server sees the FIN as EOF
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(server.read(&mut [0; 16]).unwrap(), 0);
+    assert_eq!(server.state(), State::CloseWait);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.state() != State::FinWait2 {
+        assert!(Instant::now() < deadline, "stuck in {:?}", client.state());
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(!client.is_established() && !client.is_connecting());
+
+    // then the server, which leaves the client in TIME-WAIT
+    server.shutdown(Shutdown::Write).unwrap();
+    while client.state() != State::TimeWait {
+        assert!(Instant::now() < deadline, "stuck in {:?}", client.state());
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn streams_work_with_std_io() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    // the server reads line by line through a BufReader
+    let lines = thread::spawn(move || {
+        let mut reader = io::BufReader::new(server);
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while io::BufRead::read_line(&mut reader, &mut line).unwrap() > 0 {
+            lines.push(line.trim_end().to_string());
+            line.clear();
+        }
+        lines
+    });
+    for i in 0..3 {
+        writeln!(client, "line {i}").unwrap();
+    }
+    io::copy(&mut &b"copied\n"[..], &mut client).unwrap();
+    client.flush().unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(
+        lines.join().unwrap(),
+        ["line 0", "line 1", "line 2", "copied"]
+    );
+}
+
+#[test]
+fn flush_waits_for_the_ack_of_everything_written() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    let (mut stream, iss) = accept_from(&peer, &listener);
+
+    // with nothing acknowledged flush gives up at the write timeout
+    stream.write_all(&[4; 100]).unwrap();
+    stream
+        .set_write_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let err = stream.flush().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    // it returns once the peer acknowledges the last byte, not before
+    stream.set_write_timeout(None).unwrap();
+    let flush = thread::spawn(move || stream.flush());
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(51))))
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(!flush.is_finished());
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(101))))
+        .unwrap();
+    flush.join().unwrap().unwrap();
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn abort_resets_instead_of_closing() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client.write_all(b"never read").unwrap();
+
+    client.abort().unwrap();
+    assert_eq!(client.state(), State::Closed);
+    // the connection is gone once the packet loop sent the RST
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !link.client.connections().established().is_empty() {
+        assert!(Instant::now() < deadline, "aborted connection kept");
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // the peer sees a reset, not an orderly EOF
+    let mut buf = [0; 64];
+    let err = loop {
+        match server.read(&mut buf) {
+            Ok(0) => panic!("orderly EOF after abort"),
+            Ok(_) => continue,
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn urgent_data_is_delivered_in_band() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    let (mut stream, iss) = accept_from(&peer, &listener);
+
+    // the first three bytes are urgent
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+        .tcp(40000, 8080, 1001, 1024)
+        .ack(iss.wrapping_add(1))
+        .urg(3);
+    let mut pkt = Vec::with_capacity(builder.size(6));
+    builder.write(&mut pkt, b"abcdef").unwrap();
+    peer.inject(&pkt).unwrap();
+
+    let mut buf = [0; 6];
+    stream.read_exact(&mut buf[..1]).unwrap();
+    assert!(stream.has_urgent_data());
+    stream.read_exact(&mut buf[1..]).unwrap();
+    assert_eq!(&buf, b"abcdef");
+    assert!(!stream.has_urgent_data());
+
+    // the loop is still serving, past the ACK of the data
+    peer.inject(&segment(40001, 1000, |b| b.syn())).unwrap();
+    while !capture_tcp(&peer).syn {}
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+// an IPv6 SYN from [fd00::2]:40000 to [fd00::1]:8080 behind a routing header,
+// which is followed by `next_header`
+fn routed_syn_v6(next_header: etherparse::IpNumber) -> Vec<u8> {
+    let (src, dst) = (
+        "fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+        "fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+    );
+    let mut tcp = etherparse::TcpHeader::new(40000, 8080, 1000, 1024);
+    tcp.syn = true;
+    tcp.checksum = tcp.calc_checksum_ipv6_raw(src, dst, &[]).unwrap();
+    // routing type 0 with no segments left, so it is passed over
+    let routing = etherparse::Ipv6RawExtHeader::new_raw(next_header, &[0, 0, 0, 0, 0, 0]).unwrap();
+    let ip = etherparse::Ipv6Header {
+        payload_length: (routing.header_len() + tcp.header_len()) as u16,
+        next_header: etherparse::IpNumber::IPV6_ROUTE_HEADER,
+        hop_limit: 64,
+        source: src,
+        destination: dst,
+        ..Default::default()
+    };
+    let mut pkt = ip.to_bytes().to_vec();
+    pkt.extend_from_slice(&routing.to_bytes());
+    pkt.extend_from_slice(&tcp.to_bytes());
+    pkt
+}
+
+#[test]
+fn tcp_behind_an_ipv6_routing_header() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let _listener = TcpListener::bind(addr("[fd00::1]:8080"), mgr.clone()).unwrap();
+
+    // whatever ends the chain if not TCP is dropped
+    assert_dropped(&peer, &routed_syn_v6(etherparse::IpNumber::UDP));
+
+    peer.inject(&routed_syn_v6(etherparse::IpNumber::TCP))
+        .unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert!(syn_ack.syn && syn_ack.ack);
+    assert_eq!(syn_ack.destination_port, 40000);
+    assert_eq!(syn_ack.acknowledgment_number, 1001);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn dropped_listener_frees_its_port_and_reuse_addr_rebinds_over_connections() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+
+    // with nothing left on it, the port of a dropped listener can be bound again
+    drop(TcpListener::bind(addr(SERVER), mgr.clone()).unwrap());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    assert!(matches!(
+        TcpListener::bind(addr(SERVER), mgr.clone()),
+        Err(TcpError::AddrInUse)
+    ));
+
+    // an accepted connection outlives its listener and keeps the port taken
+    let (_stream, _) = accept_from(&peer, &listener);
+    drop(listener);
+    assert!(matches!(
+        TcpListener::bind(addr(SERVER), mgr.clone()),
+        Err(TcpError::AddrInUse)
+    ));
+
+    // unless reuse_addr is set, which combines with the other options
+    let options = SocketOptions {
+        reuse_addr: true,
+        backlog: 1,
+        ..Default::default()
+    };
+    let _listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+    peer.inject(&segment(40001, 1000, |b| b.syn())).unwrap();
+    assert!(capture_tcp(&peer).syn);
+    assert_dropped(&peer, &segment(40002, 1000, |b| b.syn()));
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn metrics_count_the_recovery_from_a_lossy_link() {
+    // every other data segment is lost the first time it is sent
+    let link = Link::with_client(|dev| {
+        let mut sent = HashSet::new();
+        dev.set_outbound_filter(Some(Box::new(move |pkt| match data_seq(pkt) {
+            Some(seq) if sent.insert(seq) && sent.len() % 2 == 0 => Verdict::Drop,
+            _ => Verdict::Accept,
+        })));
+    });
+    // windows of several segments, so what follows a lost segment arrives ahead of it
+    let options = SocketOptions {
+        rx_buffer: 8192,
+        tx_buffer: 8192,
+        ..Default::default()
+    };
+    let listener =
+        TcpListener::bind_with_options(addr(SERVER), link.server.clone(), options).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    let mut client =
+        TcpStream::connect_with_options(addr(CLIENT), addr(SERVER), link.client.clone(), options)
+            .unwrap();
+    let data = vec![7; 32 * 1024];
+    client.write_all(&data).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(server.join().unwrap(), data);
+
+    // the sender counts what it resent and the duplicate ACKs for the holes,
+    // the receiver the segments that arrived ahead of them
+    let (client, server) = (link.client.metrics(), link.server.metrics());
+    assert!(client.retransmits > 0);
+    assert!(client.dup_acks > 0);
+    assert!(server.out_of_order > 0);
+}
+
+#[test]
+fn idle_connection_is_reset_after_the_idle_timeout() {
+    let link = Link::new();
+    let listener = TcpListener::bind(addr(SERVER), link.server.clone()).unwrap();
+    let mut client = TcpStream::connect(addr(CLIENT), addr(SERVER), link.client.clone()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    client
+        .set_idle_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+
+    // traffic keeps the connection alive past the timeout
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(100));
+        client.write_all(&[1]).unwrap();
+        server.read_exact(&mut [0]).unwrap();
+    }
+    assert_eq!(client.state(), State::Estab);
+
+    // once it stops, the connection is reset on both ends
+    let start = Instant::now();
+    let err = client.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    let err = server.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn pushed_data_wakes_a_blocked_reader_before_the_ack_goes_out() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        ack_delay: Duration::from_millis(500),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+    let (mut stream, iss) = accept_from(&peer, &listener);
+    let reader = thread::spawn(move || {
+        let mut buf = [0; 16];
+        let n = stream.read(&mut buf).unwrap();
+        (buf[..n].to_vec(), Instant::now(), stream)
+    });
+    thread::sleep(Duration::from_millis(50));
+
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+        .tcp(40000, 8080, 1001, 1024)
+        .ack(iss.wrapping_add(1))
+        .psh();
+    let mut pkt = Vec::with_capacity(builder.size(2));
+    builder.write(&mut pkt, b"hi").unwrap();
+    let sent = Instant::now();
+    peer.inject(&pkt).unwrap();
+
+    // the reader has the data while its ACK is still being held back
+    let (data, woken, _stream) = reader.join().unwrap();
+    assert_eq!(data, b"hi");
+    assert!(woken - sent < Duration::from_millis(200));
+    let ack = peer.capture(Some(Duration::from_millis(1)));
+    assert_eq!(ack.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(capture_tcp(&peer).acknowledgment_number, 1003);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn accepted_connections_advertise_the_listener_buffer() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        rx_buffer: 8192,
+        ack_delay: Duration::ZERO,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = capture_tcp(&peer);
+    assert_eq!(syn_ack.window_size, 8192);
+    let iss = syn_ack.sequence_number;
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(1))))
+        .unwrap();
+    let (_stream, _) = listener.accept().unwrap();
+
+    // the connection buffers with the listener's capacity, text takes room from it
+    let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 2], [10, 0, 0, 1], 64)
+        .tcp(40000, 8080, 1001, 1024)
+        .ack(iss.wrapping_add(1));
+    let mut pkt = Vec::with_capacity(builder.size(1000));
+    builder.write(&mut pkt, &[1; 1000]).unwrap();
+    peer.inject(&pkt).unwrap();
+    let ack = capture_tcp(&peer);
+    assert_eq!(ack.acknowledgment_number, 2001);
+    assert_eq!(ack.window_size, 8192 - 1000);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn packets_carry_the_configured_ttl() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let options = SocketOptions {
+        ttl: 3,
+        ..Default::default()
+    };
+    let listener = TcpListener::bind_with_options(addr(SERVER), mgr.clone(), options).unwrap();
+
+    // the listener's TTL goes on the SYN-ACK and is inherited by the connection
+    peer.inject(&segment(40000, 1000, |b| b.syn())).unwrap();
+    let syn_ack = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(
+        etherparse::Ipv4HeaderSlice::from_slice(&syn_ack)
+            .unwrap()
+            .ttl(),
+        3
+    );
+    let iss = etherparse::SlicedPacket::from_ip(&syn_ack)
+        .ok()
+        .and_then(|pkt| match pkt.transport {
+            Some(etherparse::TransportSlice::Tcp(tcp)) => Some(tcp.sequence_number()),
+            _ => None,
+        })
+        .unwrap();
+    peer.inject(&segment(40000, 1001, |b| b.ack(iss.wrapping_add(1))))
+        .unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+    assert_eq!(stream.ttl().unwrap(), 3);
+
+    // a TTL set on the connection applies to what it sends from then on
+    assert_eq!(
+        stream.set_ttl(0).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    stream.set_ttl(200).unwrap();
+    assert_eq!(stream.ttl().unwrap(), 200);
+    stream.write_all(b"hop").unwrap();
+    let data = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(
+        etherparse::Ipv4HeaderSlice::from_slice(&data)
+            .unwrap()
+            .ttl(),
+        200
+    );
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn packets_carry_the_configured_tos_and_traffic_class() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+
+    // IPv4: set on the connection, only the DSCP bits are kept
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    let (mut stream, _) = accept_from(&peer, &listener);
+    stream.set_tos(0xb9).unwrap();
+    assert_eq!(stream.tos().unwrap(), 0xb8);
+    stream.write_all(b"v4").unwrap();
+    let pkt = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    let ip = etherparse::Ipv4HeaderSlice::from_slice(&pkt).unwrap();
+    assert_eq!((ip.dcp().value() << 2) | ip.ecn().value(), 0xb8);
+
+    // IPv6: set on the listener, its SYN-ACK already carries the traffic class
+    let options = SocketOptions {
+        tos: 0x28,
+        ..Default::default()
+    };
+    let _listener =
+        TcpListener::bind_with_options(addr("[fd00::1]:8081"), mgr.clone(), options).unwrap();
+    let builder = etherparse::PacketBuilder::ipv6(
+        "fd00::2".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+        "fd00::1".parse::<std::net::Ipv6Addr>().unwrap().octets(),
+        64,
+    )
+    .tcp(40000, 8081, 1000, 1024)
+    .syn();
+    let mut syn = Vec::with_capacity(builder.size(0));
+    builder.write(&mut syn, &[]).unwrap();
+    peer.inject(&syn).unwrap();
+    let pkt = peer.capture(Some(Duration::from_secs(1))).unwrap();
+    let ip = etherparse::Ipv6HeaderSlice::from_slice(&pkt).unwrap();
+    assert_eq!(ip.traffic_class(), 0x28);
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}
+
+#[test]
+fn higher_priority_connection_sends_first_under_contention() {
+    let (dev, peer) = LoopbackDevice::pair().unwrap();
+    let mgr = Arc::new(ConnectionManager::new());
+    let stack = spawn_stack(dev, mgr.clone());
+    let listener = TcpListener::bind(addr(SERVER), mgr.clone()).unwrap();
+    let (mut low, _) = accept_from(&peer, &listener);
+    peer.inject(&segment(40001, 5000, |b| b.syn())).unwrap();
+    let iss = capture_tcp(&peer).sequence_number;
+    peer.inject(&segment(40001, 5001, |b| b.ack(iss.wrapping_add(1))))
+        .unwrap();
+    let (mut high, _) = listener.accept().unwrap();
+    high.set_priority(7).unwrap();
+
+    // both have data queued by the time the packet loop gets to them,
+    // the one written last but with the higher priority goes out first
+    {
+        let _conns = mgr.connections();
+        low.write_all(b"bulk").unwrap();
+        high.write_all(b"interactive").unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let first = capture_tcp(&peer);
+    let second = capture_tcp(&peer);
+    assert_eq!(first.destination_port, 40001);
+    assert_eq!(second.destination_port, 40000);
+
+    // a connection that is gone can't be given a priority
+    high.abort().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while mgr.connections().established().len() > 1 {
+        assert!(Instant::now() < deadline, "aborted connection kept");
+        thread::sleep(Duration::from_millis(1));
+    }
+    let err = high.set_priority(1).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    low.set_priority(1).unwrap();
+
+    mgr.shutdown();
+    stack.join().unwrap();
+}